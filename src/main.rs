@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 use std::env::args;
+use std::io::{Read, Write};
 use std::rc::Rc;
 
-use image::{Rgb, RgbImage};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Color {
@@ -12,9 +13,57 @@ pub enum Color {
     White,
     Yellow,
     Black,
+    Rgb([u8; 3]),
+    Rgba([u8; 4]),
+    /// Leaves whatever was previously drawn untouched.
+    None,
+}
+
+/// White, the color of an empty canvas.
+impl Default for Color {
+    fn default() -> Self {
+        Color::White
+    }
 }
 
 impl Color {
+    /// The color as written in FDL. Colors with transparency other than
+    /// `None` can't be written.
+    pub fn to_fdl(&self) -> Option<String> {
+        Some(match self {
+            Color::Blue => "b".to_string(),
+            Color::Green => "g".to_string(),
+            Color::Red => "r".to_string(),
+            Color::White => "w".to_string(),
+            Color::Yellow => "y".to_string(),
+            Color::Black => "s".to_string(),
+            Color::None => "none".to_string(),
+            Color::Rgb([r, g, b]) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+            Color::Rgba(_) => return None,
+        })
+    }
+
+    /// The known color closest to this one, by straight line distance between
+    /// their RGB values. Known colors are returned as they are, and so are
+    /// transparent and translucent colors.
+    pub fn nearest_named(&self) -> Color {
+        let Rgb(rgb) = match self {
+            Color::Rgb(_) => self.to_rgb(),
+            _ => return *self,
+        };
+        let distance = |Rgb(known): Rgb<u8>| {
+            (0..3)
+                .map(|i| (rgb[i] as i32 - known[i] as i32).pow(2))
+                .sum::<i32>()
+        };
+        known_colors()
+            .into_iter()
+            .filter(|(_, known)| *known != Color::None)
+            .min_by_key(|(_, known)| distance(known.to_rgb()))
+            .map(|(_, known)| known)
+            .unwrap_or(*self)
+    }
+
     pub fn to_rgb(&self) -> Rgb<u8> {
         match self {
             Color::Blue => [0, 0, 255].into(),
@@ -23,7 +72,119 @@ impl Color {
             Color::White => [255, 255, 255].into(),
             Color::Yellow => [255, 255, 0].into(),
             Color::Black => [0, 0, 0].into(),
+            Color::Rgb(rgb) => (*rgb).into(),
+            Color::Rgba([r, g, b, _]) => [*r, *g, *b].into(),
+            Color::None => [0, 0, 0].into(),
+        }
+    }
+
+    /// The relative luminance as defined by WCAG, from 0 for black to 1 for
+    /// white.
+    pub fn luminance(&self) -> f32 {
+        let Rgb([r, g, b]) = self.to_rgb();
+        0.2126 * srgb_to_linear(r) + 0.7152 * srgb_to_linear(g) + 0.0722 * srgb_to_linear(b)
+    }
+
+    /// The WCAG contrast ratio between two colors, from 1 for identical
+    /// luminance up to 21 for black against white.
+    pub fn contrast_ratio(&self, other: &Color) -> f32 {
+        let (a, b) = (self.luminance(), other.luminance());
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    /// Multiplies each channel by the matching channel of `tint`, as if
+    /// seen through colored glass. Transparency is left as it is.
+    pub fn multiply(&self, tint: &Color) -> Color {
+        let Rgb(t) = tint.to_rgb();
+        let channel = |c: u8, t: u8| ((c as u32 * t as u32 + 127) / 255) as u8;
+        match self {
+            Color::None => Color::None,
+            Color::Rgba([r, g, b, a]) => {
+                Color::Rgba([channel(*r, t[0]), channel(*g, t[1]), channel(*b, t[2]), *a])
+            }
+            _ => {
+                let Rgb([r, g, b]) = self.to_rgb();
+                Color::Rgb([channel(r, t[0]), channel(g, t[1]), channel(b, t[2])])
+            }
+        }
+    }
+
+    pub fn to_rgba(&self) -> Rgba<u8> {
+        match self {
+            Color::Rgba(rgba) => (*rgba).into(),
+            Color::None => [0, 0, 0, 0].into(),
+            _ => {
+                let Rgb([r, g, b]) = self.to_rgb();
+                [r, g, b, 255].into()
+            }
+        }
+    }
+}
+
+/// Converts an sRGB channel to linear light, between 0 and 1.
+pub fn srgb_to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts linear light, between 0 and 1, back to an sRGB channel.
+pub fn linear_to_srgb(linear: f32) -> u8 {
+    let l = linear.clamp(0.0, 1.0);
+    let c = if l <= 0.003_130_8 {
+        l * 12.92
+    } else {
+        1.055 * l.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
+
+/// How colors are combined where they meet.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Blending {
+    /// Averages the sRGB values directly, which is quick but makes mixed
+    /// colors come out too dark.
+    Srgb,
+    /// Averages in linear light, the way the colors would mix physically.
+    Linear,
+}
+
+impl Blending {
+    /// Averages any number of colors.
+    pub fn average(self, colors: &[Rgb<u8>]) -> Rgb<u8> {
+        let count = colors.len().max(1);
+        let mut channels = [0u8; 3];
+        for (i, channel) in channels.iter_mut().enumerate() {
+            *channel = match self {
+                Blending::Srgb => {
+                    let total = colors.iter().map(|c| c.0[i] as usize).sum::<usize>();
+                    ((total + count / 2) / count) as u8
+                }
+                Blending::Linear => {
+                    let total = colors.iter().map(|c| srgb_to_linear(c.0[i])).sum::<f32>();
+                    linear_to_srgb(total / count as f32)
+                }
+            };
+        }
+        channels.into()
+    }
+
+    /// Mixes `a` into `b`, where `t` is how much of `b` to take.
+    pub fn mix(self, a: Rgb<u8>, b: Rgb<u8>, t: f32) -> Rgb<u8> {
+        let t = t.clamp(0.0, 1.0);
+        let mut channels = [0u8; 3];
+        for (i, channel) in channels.iter_mut().enumerate() {
+            *channel = match self {
+                Blending::Srgb => (a.0[i] as f32 * (1.0 - t) + b.0[i] as f32 * t).round() as u8,
+                Blending::Linear => {
+                    linear_to_srgb(srgb_to_linear(a.0[i]) * (1.0 - t) + srgb_to_linear(b.0[i]) * t)
+                }
+            };
         }
+        channels.into()
     }
 }
 
@@ -31,29 +192,96 @@ impl std::str::FromStr for Color {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "b" => Ok(Color::Blue),
-            "g" => Ok(Color::Green),
-            "r" => Ok(Color::Red),
-            "w" => Ok(Color::White),
-            "y" => Ok(Color::Yellow),
-            "s" => Ok(Color::Black),
-            _ => Err(()),
-        }
+        NAMED_COLORS
+            .iter()
+            .find(|(code, _)| *code == s)
+            .map(|(_, color)| *color)
+            .or_else(|| to_hex_color(s).map(Color::Rgb))
+            .ok_or(())
+    }
+}
+
+/// The colors that have a name in FDL, by the code they are written as.
+const NAMED_COLORS: [(&str, Color); 7] = [
+    ("b", Color::Blue),
+    ("g", Color::Green),
+    ("r", Color::Red),
+    ("w", Color::White),
+    ("y", Color::Yellow),
+    ("s", Color::Black),
+    ("none", Color::None),
+];
+
+/// Parses a six digit hex color, written either bare (`ff0000`) or with a
+/// `#` or `0x` prefix. Every character after the prefix has to be a hex
+/// digit, so stray whitespace makes the whole color invalid.
+fn to_hex_color(s: &str) -> Option<[u8; 3]> {
+    let digits = s
+        .strip_prefix('#')
+        .or_else(|| s.strip_prefix("0x"))
+        .unwrap_or(s);
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
+
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Every color code understood by the parser, along with its color.
+pub fn known_colors() -> Vec<(&'static str, Color)> {
+    NAMED_COLORS.to_vec()
 }
 
 pub trait MsPaint {
     fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color);
     fn width(&self) -> u32;
     fn height(&self) -> u32;
+
+    /// Paints the whole canvas in one color.
+    fn clear(&mut self, color: &Color) {
+        let (width, height) = (self.width(), self.height());
+        self.rectangle(0, 0, width, height, color);
+    }
+}
+
+/// Lets a borrowed canvas, including a `&mut dyn MsPaint` picked at runtime,
+/// stand in for the canvas itself.
+impl<P: MsPaint + ?Sized> MsPaint for &mut P {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        (**self).rectangle(left, top, width, height, color);
+    }
+
+    fn width(&self) -> u32 {
+        (**self).width()
+    }
+
+    fn height(&self) -> u32 {
+        (**self).height()
+    }
+
+    fn clear(&mut self, color: &Color) {
+        (**self).clear(color);
+    }
 }
 
 impl MsPaint for RgbImage {
+    /// Translucent colors are blended with what is already there.
     fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        let rgba = color.to_rgba();
+        if rgba.0[3] == 0 {
+            return;
+        }
+
         for y in top..(top + height) {
             for x in left..(left + width) {
-                self[(x, y)] = color.to_rgb();
+                self[(x, y)] = if rgba.0[3] == 255 {
+                    color.to_rgb()
+                } else {
+                    let Rgb([r, g, b]) = self[(x, y)];
+                    let Rgba([r, g, b, _]) = blend(rgba, [r, g, b, 255].into());
+                    Rgb([r, g, b])
+                };
             }
         }
     }
@@ -65,226 +293,6737 @@ impl MsPaint for RgbImage {
     fn height(&self) -> u32 {
         RgbImage::height(self)
     }
+
+    fn clear(&mut self, color: &Color) {
+        // Only an opaque color covers what is already there
+        if color.to_rgba().0[3] < 255 {
+            let (width, height) = (self.width(), self.height());
+            self.rectangle(0, 0, width, height, color);
+            return;
+        }
+
+        let rgb = color.to_rgb();
+        for pixel in self.pixels_mut() {
+            *pixel = rgb;
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum UnresolvedFlagGeometry {
-    Solid(Color),
-    Horizontal(Rc<UnresolvedFlagGeometry>, Rc<UnresolvedFlagGeometry>, u32),
-    Vertical(Rc<UnresolvedFlagGeometry>, Rc<UnresolvedFlagGeometry>, u32),
-    Tag(String, Rc<UnresolvedFlagGeometry>),
-    Reference(String),
+/// Composites `src` over `dst`, both with straight alpha. The less opaque
+/// `dst` is, the less its color counts, so that a translucent color drawn on
+/// a transparent canvas keeps its color.
+fn blend(src: Rgba<u8>, dst: Rgba<u8>) -> Rgba<u8> {
+    let Rgba([sr, sg, sb, sa]) = src;
+    let Rgba([dr, dg, db, da]) = dst;
+    let (sa, da) = (sa as u32, da as u32);
+
+    // The resulting alpha, scaled up by 255
+    let alpha = sa * 255 + da * (255 - sa);
+    if alpha == 0 {
+        return [0, 0, 0, 0].into();
+    }
+    let mix = |s: u8, d: u8| {
+        ((s as u32 * sa * 255 + d as u32 * da * (255 - sa) + alpha / 2) / alpha) as u8
+    };
+
+    [
+        mix(sr, dr),
+        mix(sg, dg),
+        mix(sb, db),
+        ((alpha + 127) / 255) as u8,
+    ]
+    .into()
 }
 
-impl UnresolvedFlagGeometry {
-    pub fn tags(&self) -> HashMap<String, Rc<UnresolvedFlagGeometry>> {
-        let mut map = HashMap::new();
-        match self {
-            UnresolvedFlagGeometry::Tag(tag, geo) => {
-                map.extend(geo.tags());
-                map.insert(tag.clone(), geo.clone());
-            }
-            UnresolvedFlagGeometry::Horizontal(car, cdr, ..)
-            | UnresolvedFlagGeometry::Vertical(car, cdr, ..) => {
-                map.extend(car.tags());
-                map.extend(cdr.tags());
+/// How the color channels of an image with transparency relate to its
+/// alpha channel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// The color as it is, whatever the alpha, which is what image files hold.
+    Straight,
+    /// The color already multiplied by the alpha, which is what GPU
+    /// pipelines usually expect of textures to blend them correctly.
+    Premultiplied,
+}
+
+impl MsPaint for RgbaImage {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        let rgba = color.to_rgba();
+        for y in top..(top + height) {
+            for x in left..(left + width) {
+                self[(x, y)] = if rgba.0[3] == 255 {
+                    rgba
+                } else {
+                    blend(rgba, self[(x, y)])
+                };
             }
-            _ => {}
         }
+    }
 
-        map
+    fn width(&self) -> u32 {
+        RgbaImage::width(self)
     }
 
-    pub fn resolve(
-        &self,
-        tags: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
-    ) -> Option<FlagGeometry> {
-        match self {
-            UnresolvedFlagGeometry::Solid(color) => Some(FlagGeometry::Solid(*color)),
-            UnresolvedFlagGeometry::Horizontal(car, cdr, pivot) => Some(FlagGeometry::Horizontal(
-                Rc::new(car.resolve(tags)?),
-                Rc::new(cdr.resolve(tags)?),
-                *pivot,
-            )),
-            UnresolvedFlagGeometry::Vertical(car, cdr, pivot) => Some(FlagGeometry::Vertical(
-                Rc::new(car.resolve(tags)?),
-                Rc::new(cdr.resolve(tags)?),
-                *pivot,
-            )),
-            UnresolvedFlagGeometry::Tag(_, geo) => geo.resolve(tags),
-            UnresolvedFlagGeometry::Reference(tag) => tags.get(tag).and_then(|x| x.resolve(tags)),
+    fn height(&self) -> u32 {
+        RgbaImage::height(self)
+    }
+}
+
+/// An axis-aligned rectangle in pixel coordinates.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(left: u32, top: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            left,
+            top,
+            width,
+            height,
+        }
+    }
+
+    pub fn right(&self) -> u32 {
+        self.left + self.width
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.top + self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// The overlap between `self` and `other`, if they overlap at all.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let left = self.left.max(other.left);
+        let top = self.top.max(other.top);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right > left && bottom > top {
+            Some(Rect::new(left, top, right - left, bottom - top))
+        } else {
+            None
         }
     }
+
+    /// The smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let left = self.left.min(other.left);
+        let top = self.top.min(other.top);
+        Rect::new(
+            left,
+            top,
+            self.right().max(other.right()) - left,
+            self.bottom().max(other.bottom()) - top,
+        )
+    }
+}
+
+/// The part of a rectangle that falls within `bounds`, for rectangles placed
+/// by offsets that may start before the bounds or reach past them. The
+/// arithmetic is done wide enough that neither can wrap around, and when
+/// nothing is left the result is an empty rectangle at the nearest edge.
+fn clamp_rect(left: i64, top: i64, width: u32, height: u32, bounds: Rect) -> Rect {
+    let clamp = |start: i64, length: u32, lower: u32, size: u32| {
+        let (lower, upper) = (lower as i64, lower as i64 + size as i64);
+        let first = start.clamp(lower, upper);
+        let last = (start + length as i64).clamp(lower, upper);
+        (first as u32, (last - first) as u32)
+    };
+    let (left, width) = clamp(left, width, bounds.left, bounds.width);
+    let (top, height) = clamp(top, height, bounds.top, bounds.height);
+    Rect::new(left, top, width, height)
 }
 
+/// A canvas that doesn't paint anything, but records every non-empty
+/// rectangle drawn into it in the order they were drawn.
 #[derive(Debug)]
-pub enum FlagGeometry {
-    Solid(Color),
-    Horizontal(Rc<FlagGeometry>, Rc<FlagGeometry>, u32),
-    Vertical(Rc<FlagGeometry>, Rc<FlagGeometry>, u32),
+pub struct RecordingCanvas {
+    width: u32,
+    height: u32,
+    pub rectangles: Vec<(Rect, Color)>,
 }
 
-impl FlagGeometry {
-    fn draw_area<P: MsPaint>(&self, buffer: &mut P, left: u32, top: u32, width: u32, height: u32) {
-        match self {
-            FlagGeometry::Solid(color) => {
-                buffer.rectangle(left, top, width, height, color);
-            }
-            FlagGeometry::Horizontal(car, cdr, pivot) => {
-                car.draw_area(buffer, left, top, (pivot * width) / 100, height);
-                cdr.draw_area(
-                    buffer,
-                    left + (pivot * width) / 100,
-                    top,
-                    ((100 - pivot) * width) / 100,
-                    height,
-                );
-            }
-            FlagGeometry::Vertical(car, cdr, pivot) => {
-                car.draw_area(buffer, left, top, width, (pivot * height) / 100);
-                cdr.draw_area(
-                    buffer,
-                    left,
-                    top + (pivot * height) / 100,
-                    width,
-                    ((100 - pivot) * height) / 100,
-                );
-            }
+impl RecordingCanvas {
+    pub fn new(width: u32, height: u32) -> RecordingCanvas {
+        RecordingCanvas {
+            width,
+            height,
+            rectangles: Vec::new(),
         }
     }
+}
 
-    pub fn draw<P: MsPaint>(&self, buffer: &mut P) {
-        self.draw_area(buffer, 0, 0, buffer.width(), buffer.height());
+impl MsPaint for RecordingCanvas {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        let rect = Rect::new(left, top, width, height);
+        if !rect.is_empty() {
+            self.rectangles.push((rect, *color));
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
     }
 }
 
-#[derive(Debug)]
-pub enum SExpr {
-    List(Vec<SExpr>),
-    Literal(String),
+/// A canvas that keeps the color last painted at each pixel, and which of
+/// them haven't been painted at all.
+struct LayerCanvas {
+    width: u32,
+    height: u32,
+    /// The part of the canvas that is kept. Anything painted outside of it
+    /// is thrown away.
+    window: Rect,
+    pixels: Vec<Option<Color>>,
 }
 
-impl SExpr {
-    pub fn parse<I>(input: &mut std::iter::Peekable<I>) -> Option<SExpr>
-    where
-        I: Iterator<Item = char>,
-    {
-        let mut sexpr = None;
-        while let Some(c) = input.peek() {
-            match sexpr {
-                None if c.is_whitespace() => {}
-                None if *c == '(' => {
-                    sexpr = Some(SExpr::List(Vec::new()));
-                }
-                None => {
-                    sexpr = Some(SExpr::Literal(c.to_string()));
-                }
-                Some(SExpr::List(_)) if *c == ')' => {
-                    input.next();
-                    break;
-                }
-                Some(SExpr::List(ref mut list)) => {
-                    list.push(SExpr::parse(input)?);
-                    continue;
-                }
-                Some(SExpr::Literal(_)) if c.is_whitespace() || *c == ')' => {
-                    break;
-                }
-                Some(SExpr::Literal(ref mut literal)) => literal.push(*c),
-            }
+impl LayerCanvas {
+    /// A canvas that only keeps the pixels within `window`, such as a single
+    /// row of a larger layer.
+    fn window(width: u32, height: u32, window: Rect) -> LayerCanvas {
+        LayerCanvas {
+            width,
+            height,
+            window,
+            pixels: vec![None; window.width as usize * window.height as usize],
+        }
+    }
 
-            input.next();
+    /// The color at a position within the window.
+    fn get(&self, x: u32, y: u32) -> Option<Color> {
+        let (x, y) = (x - self.window.left, y - self.window.top);
+        self.pixels[y as usize * self.window.width as usize + x as usize]
+    }
+}
+
+impl MsPaint for LayerCanvas {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        if *color == Color::None {
+            return;
         }
 
-        while let Some(c) = input.peek() {
-            if c.is_whitespace() {
-                input.next();
-            } else {
-                break;
+        let rect = match Rect::new(left, top, width, height).intersection(&self.window) {
+            Some(rect) => rect,
+            None => return,
+        };
+        for y in rect.top..rect.top + rect.height {
+            let row = (y - self.window.top) as usize * self.window.width as usize;
+            for x in rect.left..rect.left + rect.width {
+                self.pixels[row + (x - self.window.left) as usize] = Some(*color);
             }
         }
+    }
 
-        sexpr
+    fn width(&self) -> u32 {
+        self.width
     }
 
-    pub fn list(&self) -> Option<&[SExpr]> {
-        match self {
-            SExpr::List(list) => Some(list.as_slice()),
-            SExpr::Literal(_) => None,
-        }
+    fn height(&self) -> u32 {
+        self.height
     }
+}
 
-    pub fn literal(&self) -> Option<&str> {
-        match self {
-            SExpr::Literal(literal) => Some(literal.as_str()),
-            SExpr::List(_) => None,
+/// Wraps another canvas so that only the parts of rectangles falling within
+/// `clip` reach it.
+pub struct ClippedCanvas<'a, P: MsPaint> {
+    inner: &'a mut P,
+    clip: Rect,
+}
+
+impl<'a, P: MsPaint> ClippedCanvas<'a, P> {
+    pub fn new(inner: &'a mut P, clip: Rect) -> ClippedCanvas<'a, P> {
+        ClippedCanvas { inner, clip }
+    }
+}
+
+impl<'a, P: MsPaint> MsPaint for ClippedCanvas<'a, P> {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        if let Some(rect) = Rect::new(left, top, width, height).intersection(&self.clip) {
+            self.inner
+                .rectangle(rect.left, rect.top, rect.width, rect.height, color);
         }
     }
 
-    pub fn to_flag_geometry(&self) -> Option<UnresolvedFlagGeometry> {
-        let list = self.list()?;
+    fn width(&self) -> u32 {
+        self.inner.width()
+    }
 
-        match list {
-            [op, c] if op.literal()? == "s" => {
-                let color = c.literal().and_then(|lit| lit.parse().ok())?;
-                Some(UnresolvedFlagGeometry::Solid(color))
-            }
-            [op, pivot, car, cdr] => {
-                let pivot = pivot.literal().and_then(|lit| lit.parse().ok())?;
-                let car = Rc::new(car.to_flag_geometry()?);
-                let cdr = Rc::new(cdr.to_flag_geometry()?);
-                match op.literal()? {
-                    "h" => Some(UnresolvedFlagGeometry::Horizontal(car, cdr, pivot)),
-                    "v" => Some(UnresolvedFlagGeometry::Vertical(car, cdr, pivot)),
-                    _ => None,
-                }
-            }
-            [op, tag, geo] if op.literal()? == "t" => {
-                let tag = tag.literal()?.to_string();
-                let geo = Rc::new(geo.to_flag_geometry()?);
-                Some(UnresolvedFlagGeometry::Tag(tag, geo))
-            }
-            [op, tag] if op.literal()? == "r" => {
-                let tag = tag.literal()?.to_string();
-                Some(UnresolvedFlagGeometry::Reference(tag))
-            }
-            _ => {
-                eprintln!("{:?}", list);
-                None
-            }
-        }
+    fn height(&self) -> u32 {
+        self.inner.height()
     }
 }
 
-fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let flag = args()
-        .nth(1)
-        .and_then(|fdl| SExpr::parse(&mut fdl.chars().peekable()))
-        .and_then(|sexpr| sexpr.to_flag_geometry())
-        .and_then(|ufg| {
-            let tags = ufg.tags();
-            ufg.resolve(&tags)
-        })
-        .unwrap();
+/// Wraps another canvas, moving everything drawn up and to the left by an
+/// offset, so that a part of a larger layout lands at the origin. Nothing
+/// may be drawn above or to the left of the offset.
+struct OffsetCanvas<'a, P: MsPaint> {
+    inner: &'a mut P,
+    left: u32,
+    top: u32,
+}
 
-    eprintln!("{:#?}", flag);
+impl<'a, P: MsPaint> MsPaint for OffsetCanvas<'a, P> {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        self.inner
+            .rectangle(left - self.left, top - self.top, width, height, color);
+    }
 
-    let mut img = RgbImage::new(400, 300);
-    flag.draw(&mut img);
-    img.save("out.png")?;
+    fn width(&self) -> u32 {
+        self.left + self.inner.width()
+    }
 
-    Ok(())
+    fn height(&self) -> u32 {
+        self.top + self.inner.height()
+    }
 }
 
-#[cfg(test)]
-pub mod tests {
-    use super::*;
+/// A canvas that keeps its pixels in memory and writes them out as a plain
+/// text (`P3`) PPM file. Handy for eyeballing output without any decoder.
+#[derive(Debug)]
+pub struct PpmCanvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<Rgb<u8>>,
+}
 
-    #[test]
-    fn test_sexpr_parse() {
-        println!(
-            "{:#?}",
-            SExpr::parse(&mut "(h 33 (s b) (h 50 (s w) (s r)))".chars().peekable()).unwrap()
-        );
+impl PpmCanvas {
+    pub fn new(width: u32, height: u32) -> PpmCanvas {
+        PpmCanvas {
+            width,
+            height,
+            pixels: vec![Color::Black.to_rgb(); (width * height) as usize],
+        }
+    }
+
+    pub fn write(&self, mut w: impl Write) -> std::io::Result<()> {
+        writeln!(w, "P3")?;
+        writeln!(w, "{} {}", self.width, self.height)?;
+        writeln!(w, "255")?;
+        for row in self.pixels.chunks(self.width.max(1) as usize) {
+            let row = row
+                .iter()
+                .map(|Rgb([r, g, b])| format!("{} {} {}", r, g, b))
+                .collect::<Vec<_>>();
+            writeln!(w, "{}", row.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl MsPaint for PpmCanvas {
+    /// Translucent colors are blended with what is already there, the same
+    /// way as on an `RgbImage`.
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        let rgba = color.to_rgba();
+        if rgba.0[3] == 0 {
+            return;
+        }
+
+        for y in top..(top + height) {
+            for x in left..(left + width) {
+                let pixel = &mut self.pixels[(y * self.width + x) as usize];
+                *pixel = if rgba.0[3] == 255 {
+                    color.to_rgb()
+                } else {
+                    let Rgb([r, g, b]) = *pixel;
+                    let Rgba([r, g, b, _]) = blend(rgba, [r, g, b, 255].into());
+                    Rgb([r, g, b])
+                };
+            }
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// A canvas that records each rectangle as an SVG `<rect>` element.
+#[derive(Debug)]
+pub struct SvgCanvas {
+    width: u32,
+    height: u32,
+    elements: Vec<String>,
+    depth: usize,
+}
+
+impl SvgCanvas {
+    pub fn new(width: u32, height: u32) -> SvgCanvas {
+        SvgCanvas {
+            width,
+            height,
+            elements: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    /// Opens a `<g>` element with the given id, which holds everything drawn
+    /// until the matching `end_group`.
+    pub fn begin_group(&mut self, id: &str) {
+        self.push(format!(r#"<g id="{}">"#, escape_xml(id)));
+        self.depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        self.depth = self.depth.saturating_sub(1);
+        self.push("</g>".to_string());
+    }
+
+    fn push(&mut self, element: String) {
+        self.elements
+            .push(format!("{}{}", "  ".repeat(self.depth), element));
+    }
+
+    pub fn write(&self, mut w: impl Write) -> std::io::Result<()> {
+        writeln!(
+            w,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{1}" viewBox="0 0 {0} {1}">"#,
+            self.width, self.height
+        )?;
+        for element in &self.elements {
+            writeln!(w, "  {}", element)?;
+        }
+        writeln!(w, "</svg>")
+    }
+}
+
+impl MsPaint for SvgCanvas {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        if *color == Color::None || width == 0 || height == 0 {
+            return;
+        }
+
+        let Rgba([r, g, b, a]) = color.to_rgba();
+        let opacity = if a == 255 {
+            String::new()
+        } else {
+            format!(r#" fill-opacity="{:.3}""#, a as f32 / 255.0)
+        };
+        self.push(format!(
+            r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#{:02x}{:02x}{:02x}"{}/>"##,
+            left, top, width, height, r, g, b, opacity
+        ));
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// Escapes the characters that can't appear as is in XML text or attributes.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The axis along which an area is divided.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Side by side, dividing the width.
+    Horizontal,
+    /// On top of each other, dividing the height.
+    Vertical,
+}
+
+impl Direction {
+    /// The extent of an area along this direction.
+    pub fn length(&self, width: u32, height: u32) -> u32 {
+        match self {
+            Direction::Horizontal => width,
+            Direction::Vertical => height,
+        }
+    }
+}
+
+impl std::str::FromStr for Direction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h" => Ok(Direction::Horizontal),
+            "v" => Ok(Direction::Vertical),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How the colors of two overlapping layers are combined where both paint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Each channel multiplied, as with `tint`, so crossings get darker.
+    Multiply,
+    /// The average of the two colors.
+    Mix,
+}
+
+impl BlendMode {
+    pub fn apply(&self, a: &Color, b: &Color) -> Color {
+        match self {
+            BlendMode::Multiply => a.multiply(b),
+            BlendMode::Mix => {
+                let (Rgba(a), Rgba(b)) = (a.to_rgba(), b.to_rgba());
+                let channel = |i: usize| (a[i] as u32 + b[i] as u32).div_ceil(2) as u8;
+                Color::Rgba([channel(0), channel(1), channel(2), channel(3)])
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for BlendMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "multiply" => Ok(BlendMode::Multiply),
+            "mix" => Ok(BlendMode::Mix),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where a split divides its area.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Weight {
+    /// The first part takes this percentage of the length.
+    Percent(f32),
+    /// The first part is this many pixels, whatever the length.
+    Pixels(u32),
+    /// The second part is this many pixels, whatever the length.
+    PixelsAfter(u32),
+}
+
+impl Weight {
+    /// Whether this is a fixed size in pixels rather than a percentage.
+    pub fn is_pixels(self) -> bool {
+        !matches!(self, Weight::Percent(_))
+    }
+
+    /// The same division seen from the other end.
+    pub fn mirrored(self) -> Weight {
+        match self {
+            Weight::Percent(pivot) => Weight::Percent(100.0 - pivot),
+            Weight::Pixels(pixels) => Weight::PixelsAfter(pixels),
+            Weight::PixelsAfter(pixels) => Weight::Pixels(pixels),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnresolvedFlagGeometry {
+    Solid(Color),
+    Horizontal(
+        Rc<UnresolvedFlagGeometry>,
+        Rc<UnresolvedFlagGeometry>,
+        Weight,
+    ),
+    Vertical(
+        Rc<UnresolvedFlagGeometry>,
+        Rc<UnresolvedFlagGeometry>,
+        Weight,
+    ),
+    Rays(u32, Color, Color, Rc<UnresolvedFlagGeometry>),
+    Disc(f32, f32, f32, Color, Rc<UnresolvedFlagGeometry>),
+    TileFill(Direction, f32, Color, Color),
+    /// A geometry with every color multiplied by a tint when resolved.
+    Tint(Color, Rc<UnresolvedFlagGeometry>),
+    /// An image read from the given path when resolved, centered over a
+    /// field at a size given as a percentage of the area.
+    Emblem(String, f32, Option<Shadow>, Rc<UnresolvedFlagGeometry>),
+    Mask(Rc<UnresolvedFlagGeometry>, Rc<UnresolvedFlagGeometry>),
+    Grid(
+        Rc<UnresolvedFlagGeometry>,
+        Rc<UnresolvedFlagGeometry>,
+        BlendMode,
+    ),
+    /// A split with bands of a color the given number of pixels thick
+    /// inserted between its parts when resolved.
+    Fimbriation(u32, Color, Rc<UnresolvedFlagGeometry>),
+    Alpha(f32, Rc<UnresolvedFlagGeometry>),
+    /// A geometry drawn mirrored when resolved, as by `FlagGeometry::Flipped`.
+    Flipped(Direction, Rc<UnresolvedFlagGeometry>),
+    Tag(String, Rc<UnresolvedFlagGeometry>),
+    Reference(String),
+    /// A fragment to be read from the given path by `expand_includes`.
+    Include(String),
+}
+
+/// Everything resolved so far while resolving a geometry, so that nothing
+/// has to be resolved twice.
+#[derive(Default)]
+struct ResolveCache {
+    /// The geometry of each tag, shared between every reference to it.
+    tags: HashMap<String, Rc<FlagGeometry>>,
+    /// The geometry of each node by address, shared between every parent of
+    /// a subtree that is itself shared.
+    nodes: HashMap<*const UnresolvedFlagGeometry, Rc<FlagGeometry>>,
+}
+
+impl UnresolvedFlagGeometry {
+    pub fn split(
+        direction: Direction,
+        car: Rc<UnresolvedFlagGeometry>,
+        cdr: Rc<UnresolvedFlagGeometry>,
+        pivot: Weight,
+    ) -> UnresolvedFlagGeometry {
+        match direction {
+            Direction::Horizontal => UnresolvedFlagGeometry::Horizontal(car, cdr, pivot),
+            Direction::Vertical => UnresolvedFlagGeometry::Vertical(car, cdr, pivot),
+        }
+    }
+
+    /// The direction, parts and pivot of a split, or `None` for anything else.
+    fn as_split(
+        &self,
+    ) -> Option<(
+        Direction,
+        &UnresolvedFlagGeometry,
+        &UnresolvedFlagGeometry,
+        Weight,
+    )> {
+        match self {
+            UnresolvedFlagGeometry::Horizontal(car, cdr, pivot) => {
+                Some((Direction::Horizontal, car.as_ref(), cdr.as_ref(), *pivot))
+            }
+            UnresolvedFlagGeometry::Vertical(car, cdr, pivot) => {
+                Some((Direction::Vertical, car.as_ref(), cdr.as_ref(), *pivot))
+            }
+            _ => None,
+        }
+    }
+
+    /// Collects every tag defined in the geometry. When the same name is
+    /// defined more than once, the definition that comes last in the source
+    /// text wins, so a tag nested inside a same-named tag shadows it.
+    pub fn tags(&self) -> HashMap<String, Rc<UnresolvedFlagGeometry>> {
+        let mut map = HashMap::new();
+        match self {
+            UnresolvedFlagGeometry::Tag(tag, geo) => {
+                map.insert(tag.clone(), geo.clone());
+                map.extend(geo.tags());
+            }
+            UnresolvedFlagGeometry::Horizontal(car, cdr, ..)
+            | UnresolvedFlagGeometry::Vertical(car, cdr, ..)
+            | UnresolvedFlagGeometry::Mask(car, cdr)
+            | UnresolvedFlagGeometry::Grid(car, cdr, _) => {
+                map.extend(car.tags());
+                map.extend(cdr.tags());
+            }
+            UnresolvedFlagGeometry::Rays(.., field)
+            | UnresolvedFlagGeometry::Disc(.., field)
+            | UnresolvedFlagGeometry::Tint(_, field)
+            | UnresolvedFlagGeometry::Alpha(_, field)
+            | UnresolvedFlagGeometry::Flipped(_, field)
+            | UnresolvedFlagGeometry::Emblem(.., field)
+            | UnresolvedFlagGeometry::Fimbriation(.., field) => {
+                map.extend(field.tags());
+            }
+            _ => {}
+        }
+
+        map
+    }
+
+    /// Replaces every reference with the geometry of its tag. Any reference
+    /// to a tag that isn't defined fails the whole resolution, rather than
+    /// leaving a hole in the flag.
+    pub fn resolve(
+        &self,
+        tags: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
+    ) -> Result<FlagGeometry, FlagError> {
+        let mut cache = ResolveCache::default();
+        let geo = self.resolve_cached(tags, &mut cache)?;
+        drop(cache);
+
+        // Nothing else can hold on to the root once the cache is gone
+        Ok(Rc::try_unwrap(geo).unwrap_or_else(|geo| (*geo).clone()))
+    }
+
+    /// Resolves the geometry, resolving each tag and each shared subtree only
+    /// once and sharing the result between every place that refers to it. A
+    /// tree built with `Rc` children reused in many places, as in
+    /// `(h 50 x x)` for the same `x`, resolves to a tree that reuses them too
+    /// rather than to one copy for each place.
+    fn resolve_cached(
+        &self,
+        tags: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
+        cache: &mut ResolveCache,
+    ) -> Result<Rc<FlagGeometry>, FlagError> {
+        let key = self as *const UnresolvedFlagGeometry;
+        if let Some(geo) = cache.nodes.get(&key) {
+            return Ok(geo.clone());
+        }
+
+        let geo = self.resolve_node(tags, cache)?;
+        cache.nodes.insert(key, geo.clone());
+        Ok(geo)
+    }
+
+    fn resolve_node(
+        &self,
+        tags: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
+        cache: &mut ResolveCache,
+    ) -> Result<Rc<FlagGeometry>, FlagError> {
+        let geo = match self {
+            UnresolvedFlagGeometry::Solid(color) => FlagGeometry::Solid(*color),
+            UnresolvedFlagGeometry::Horizontal(car, cdr, pivot) => FlagGeometry::Horizontal(
+                car.resolve_cached(tags, cache)?,
+                cdr.resolve_cached(tags, cache)?,
+                *pivot,
+            ),
+            UnresolvedFlagGeometry::Vertical(car, cdr, pivot) => FlagGeometry::Vertical(
+                car.resolve_cached(tags, cache)?,
+                cdr.resolve_cached(tags, cache)?,
+                *pivot,
+            ),
+            UnresolvedFlagGeometry::Rays(count, center, ray, field) => {
+                FlagGeometry::Rays(*count, *center, *ray, field.resolve_cached(tags, cache)?)
+            }
+            UnresolvedFlagGeometry::Disc(x, y, radius, color, field) => {
+                FlagGeometry::Disc(*x, *y, *radius, *color, field.resolve_cached(tags, cache)?)
+            }
+            UnresolvedFlagGeometry::TileFill(direction, stripe, a, b) => {
+                FlagGeometry::TileFill(*direction, *stripe, *a, *b)
+            }
+            UnresolvedFlagGeometry::Tint(tint, geo) => {
+                geo.resolve_cached(tags, cache)?.tinted(tint)
+            }
+            UnresolvedFlagGeometry::Mask(shape, content) => FlagGeometry::Mask(
+                shape.resolve_cached(tags, cache)?,
+                content.resolve_cached(tags, cache)?,
+            ),
+            UnresolvedFlagGeometry::Grid(across, down, mode) => FlagGeometry::Grid(
+                across.resolve_cached(tags, cache)?,
+                down.resolve_cached(tags, cache)?,
+                *mode,
+            ),
+            UnresolvedFlagGeometry::Fimbriation(thickness, color, geo) => geo
+                .resolve_cached(tags, cache)?
+                .fimbriated(*thickness, color),
+            UnresolvedFlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, geo.resolve_cached(tags, cache)?)
+            }
+            UnresolvedFlagGeometry::Flipped(direction, geo) => {
+                geo.resolve_cached(tags, cache)?.flipped(*direction)
+            }
+            UnresolvedFlagGeometry::Emblem(path, size, shadow, field) => FlagGeometry::Emblem(
+                Rc::new(load_emblem(path)?),
+                *size,
+                *shadow,
+                field.resolve_cached(tags, cache)?,
+            ),
+            // A definition that isn't shadowed is the same geometry that the
+            // references to it resolve to
+            UnresolvedFlagGeometry::Tag(tag, geo)
+                if tags.get(tag).is_some_and(|def| Rc::ptr_eq(def, geo)) =>
+            {
+                return Self::resolve_tag(tag, tags, cache);
+            }
+            UnresolvedFlagGeometry::Tag(_, geo) => return geo.resolve_cached(tags, cache),
+            UnresolvedFlagGeometry::Reference(tag) => return Self::resolve_tag(tag, tags, cache),
+            UnresolvedFlagGeometry::Include(path) => {
+                return Err(FlagError::IncludeNotFound(path.clone()))
+            }
+        };
+
+        Ok(Rc::new(geo))
+    }
+
+    fn resolve_tag(
+        tag: &str,
+        tags: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
+        cache: &mut ResolveCache,
+    ) -> Result<Rc<FlagGeometry>, FlagError> {
+        if let Some(geo) = cache.tags.get(tag) {
+            return Ok(geo.clone());
+        }
+
+        let geo = tags
+            .get(tag)
+            .ok_or_else(|| FlagError::UnresolvedReference(tag.to_string()))?
+            .resolve_cached(tags, cache)?;
+        cache.tags.insert(tag.to_string(), geo.clone());
+        Ok(geo)
+    }
+
+    /// Replaces every `include` with the fragment it names, as returned by
+    /// `loader`. Fragments may include other fragments, up to
+    /// `MAX_INCLUDE_DEPTH` levels deep, but never themselves.
+    pub fn expand_includes<F>(&self, loader: &mut F) -> Result<UnresolvedFlagGeometry, FlagError>
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        self.expand_includes_with(loader, &mut Vec::new())
+    }
+
+    fn expand_includes_with<F>(
+        &self,
+        loader: &mut F,
+        stack: &mut Vec<String>,
+    ) -> Result<UnresolvedFlagGeometry, FlagError>
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        Ok(match self {
+            UnresolvedFlagGeometry::Include(path) => {
+                if stack.contains(path) {
+                    return Err(FlagError::IncludeCycle(path.clone()));
+                }
+                if stack.len() >= MAX_INCLUDE_DEPTH {
+                    return Err(FlagError::IncludeTooDeep(path.clone()));
+                }
+
+                let source =
+                    loader(path).ok_or_else(|| FlagError::IncludeNotFound(path.clone()))?;
+                let fragment = SExpr::parse(&mut source.chars().peekable())
+                    .ok_or_else(|| FlagError::InvalidExpression(source.clone()))?
+                    .to_flag_geometry()?;
+
+                stack.push(path.clone());
+                let expanded = fragment.expand_includes_with(loader, stack);
+                stack.pop();
+                return expanded;
+            }
+            UnresolvedFlagGeometry::Horizontal(car, cdr, pivot) => {
+                UnresolvedFlagGeometry::Horizontal(
+                    Rc::new(car.expand_includes_with(loader, stack)?),
+                    Rc::new(cdr.expand_includes_with(loader, stack)?),
+                    *pivot,
+                )
+            }
+            UnresolvedFlagGeometry::Vertical(car, cdr, pivot) => UnresolvedFlagGeometry::Vertical(
+                Rc::new(car.expand_includes_with(loader, stack)?),
+                Rc::new(cdr.expand_includes_with(loader, stack)?),
+                *pivot,
+            ),
+            UnresolvedFlagGeometry::Rays(count, center, ray, field) => {
+                UnresolvedFlagGeometry::Rays(
+                    *count,
+                    *center,
+                    *ray,
+                    Rc::new(field.expand_includes_with(loader, stack)?),
+                )
+            }
+            UnresolvedFlagGeometry::Disc(x, y, radius, color, field) => {
+                UnresolvedFlagGeometry::Disc(
+                    *x,
+                    *y,
+                    *radius,
+                    *color,
+                    Rc::new(field.expand_includes_with(loader, stack)?),
+                )
+            }
+            UnresolvedFlagGeometry::Tint(tint, geo) => UnresolvedFlagGeometry::Tint(
+                *tint,
+                Rc::new(geo.expand_includes_with(loader, stack)?),
+            ),
+            UnresolvedFlagGeometry::Emblem(path, size, shadow, field) => {
+                UnresolvedFlagGeometry::Emblem(
+                    path.clone(),
+                    *size,
+                    *shadow,
+                    Rc::new(field.expand_includes_with(loader, stack)?),
+                )
+            }
+            UnresolvedFlagGeometry::Mask(shape, content) => UnresolvedFlagGeometry::Mask(
+                Rc::new(shape.expand_includes_with(loader, stack)?),
+                Rc::new(content.expand_includes_with(loader, stack)?),
+            ),
+            UnresolvedFlagGeometry::Grid(across, down, mode) => UnresolvedFlagGeometry::Grid(
+                Rc::new(across.expand_includes_with(loader, stack)?),
+                Rc::new(down.expand_includes_with(loader, stack)?),
+                *mode,
+            ),
+            UnresolvedFlagGeometry::Fimbriation(thickness, color, geo) => {
+                UnresolvedFlagGeometry::Fimbriation(
+                    *thickness,
+                    *color,
+                    Rc::new(geo.expand_includes_with(loader, stack)?),
+                )
+            }
+            UnresolvedFlagGeometry::Alpha(opacity, geo) => UnresolvedFlagGeometry::Alpha(
+                *opacity,
+                Rc::new(geo.expand_includes_with(loader, stack)?),
+            ),
+            UnresolvedFlagGeometry::Flipped(direction, geo) => UnresolvedFlagGeometry::Flipped(
+                *direction,
+                Rc::new(geo.expand_includes_with(loader, stack)?),
+            ),
+            UnresolvedFlagGeometry::Tag(tag, geo) => UnresolvedFlagGeometry::Tag(
+                tag.clone(),
+                Rc::new(geo.expand_includes_with(loader, stack)?),
+            ),
+            UnresolvedFlagGeometry::Solid(_)
+            | UnresolvedFlagGeometry::TileFill(..)
+            | UnresolvedFlagGeometry::Reference(_) => self.clone(),
+        })
+    }
+
+    /// Finds the regions covered by the tag `name` when the flag is drawn at
+    /// the given size, both where it is defined and where it is referenced.
+    pub fn tag_regions(&self, name: &str, width: u32, height: u32) -> Vec<Rect> {
+        let mut regions = Vec::new();
+        self.tag_regions_in(name, Rect::new(0, 0, width, height), &mut regions);
+        regions
+    }
+
+    fn tag_regions_in(&self, name: &str, area: Rect, regions: &mut Vec<Rect>) {
+        match self {
+            UnresolvedFlagGeometry::Horizontal(..) | UnresolvedFlagGeometry::Vertical(..) => {
+                for (part, rect) in split_run(self, UnresolvedFlagGeometry::as_split, area) {
+                    part.tag_regions_in(name, rect, regions);
+                }
+            }
+            UnresolvedFlagGeometry::Rays(.., field)
+            | UnresolvedFlagGeometry::Disc(.., field)
+            | UnresolvedFlagGeometry::Tint(_, field)
+            | UnresolvedFlagGeometry::Alpha(_, field)
+            | UnresolvedFlagGeometry::Emblem(.., field)
+            | UnresolvedFlagGeometry::Fimbriation(.., field) => {
+                field.tag_regions_in(name, area, regions);
+            }
+            UnresolvedFlagGeometry::Flipped(direction, geo) => {
+                let mut flipped = Vec::new();
+                geo.tag_regions_in(name, area, &mut flipped);
+                regions.extend(flipped.into_iter().map(|rect| match direction {
+                    Direction::Horizontal => Rect {
+                        left: 2 * area.left + area.width - rect.left - rect.width,
+                        ..rect
+                    },
+                    Direction::Vertical => Rect {
+                        top: 2 * area.top + area.height - rect.top - rect.height,
+                        ..rect
+                    },
+                }));
+            }
+            UnresolvedFlagGeometry::Mask(shape, content)
+            | UnresolvedFlagGeometry::Grid(shape, content, _) => {
+                shape.tag_regions_in(name, area, regions);
+                content.tag_regions_in(name, area, regions);
+            }
+            UnresolvedFlagGeometry::Tag(tag, geo) => {
+                if tag == name {
+                    regions.push(area);
+                }
+                geo.tag_regions_in(name, area, regions);
+            }
+            UnresolvedFlagGeometry::Reference(tag) => {
+                if tag == name {
+                    regions.push(area);
+                }
+            }
+            UnresolvedFlagGeometry::Solid(_)
+            | UnresolvedFlagGeometry::TileFill(..)
+            | UnresolvedFlagGeometry::Include(_) => {}
+        }
+    }
+
+    /// Draws the flag as SVG with everything under a tag definition grouped
+    /// in a `<g>` named after the tag. References are drawn like the tag they
+    /// refer to but aren't grouped, since ids have to be unique. Tags nested
+    /// inside rays, discs, tints or emblems are drawn as part of those.
+    pub fn draw_svg(&self, svg: &mut SvgCanvas) -> Result<(), FlagError> {
+        let area = Rect::new(0, 0, svg.width(), svg.height());
+        self.draw_svg_in(svg, &self.tags(), area)
+    }
+
+    fn draw_svg_in(
+        &self,
+        svg: &mut SvgCanvas,
+        tags: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
+        area: Rect,
+    ) -> Result<(), FlagError> {
+        match self {
+            UnresolvedFlagGeometry::Horizontal(..) | UnresolvedFlagGeometry::Vertical(..) => {
+                for (part, rect) in split_run(self, UnresolvedFlagGeometry::as_split, area) {
+                    part.draw_svg_in(svg, tags, rect)?;
+                }
+                Ok(())
+            }
+            UnresolvedFlagGeometry::Tag(tag, geo) => {
+                svg.begin_group(tag);
+                geo.draw_svg_in(svg, tags, area)?;
+                svg.end_group();
+                Ok(())
+            }
+            _ => {
+                self.resolve(tags)?
+                    .draw_area(svg, area.left, area.top, area.width, area.height);
+                Ok(())
+            }
+        }
+    }
+
+    /// Resolves the geometry against its own tags along with a shared library
+    /// of fragments in `registry`, so that common parts don't have to be
+    /// defined in every flag. A tag defined in the flag itself takes
+    /// precedence over one of the same name in the registry.
+    pub fn resolve_with_registry(
+        &self,
+        registry: &HashMap<String, Rc<UnresolvedFlagGeometry>>,
+    ) -> Result<FlagGeometry, FlagError> {
+        let mut tags = registry.clone();
+        tags.extend(self.tags());
+        self.resolve(&tags)
+    }
+
+    /// The flag as seen from the back, mirrored left to right like
+    /// `FlagGeometry::mirror_horizontal`, except that whatever is under the
+    /// tags in `keep`, such as a motto that has to read the right way, keeps
+    /// its orientation. It still moves to the mirrored position, along with
+    /// every reference to it. Emblems are mirrored too, as charges are on the
+    /// back of a real flag, and included fragments are read in to be mirrored.
+    pub fn reverse_side(&self, keep: &[&str]) -> UnresolvedFlagGeometry {
+        let reverse = |geo: &Rc<UnresolvedFlagGeometry>| Rc::new(geo.reverse_side(keep));
+        match self {
+            UnresolvedFlagGeometry::Horizontal(car, cdr, pivot) => {
+                UnresolvedFlagGeometry::Horizontal(reverse(cdr), reverse(car), pivot.mirrored())
+            }
+            UnresolvedFlagGeometry::Vertical(car, cdr, pivot) => {
+                UnresolvedFlagGeometry::Vertical(reverse(car), reverse(cdr), *pivot)
+            }
+            UnresolvedFlagGeometry::Rays(count, center, ray, field) if !count.is_multiple_of(2) => {
+                UnresolvedFlagGeometry::Rays(*count, *center, *ray, reverse(field))
+            }
+            // An even number of rays is mirrored like an emblem
+            UnresolvedFlagGeometry::Rays(count, center, ray, field) => {
+                let field = UnresolvedFlagGeometry::Flipped(Direction::Horizontal, reverse(field));
+                UnresolvedFlagGeometry::Flipped(
+                    Direction::Horizontal,
+                    Rc::new(UnresolvedFlagGeometry::Rays(
+                        *count,
+                        *center,
+                        *ray,
+                        Rc::new(field),
+                    )),
+                )
+            }
+            UnresolvedFlagGeometry::Disc(x, y, radius, color, field) => {
+                UnresolvedFlagGeometry::Disc(100.0 - x, *y, *radius, *color, reverse(field))
+            }
+            UnresolvedFlagGeometry::Tint(tint, geo) => {
+                UnresolvedFlagGeometry::Tint(*tint, reverse(geo))
+            }
+            // The image is mirrored along with everything else, while the
+            // field under it is flipped back so that its own tags are kept
+            UnresolvedFlagGeometry::Emblem(path, size, shadow, field) => {
+                let field = UnresolvedFlagGeometry::Flipped(Direction::Horizontal, reverse(field));
+                UnresolvedFlagGeometry::Flipped(
+                    Direction::Horizontal,
+                    Rc::new(UnresolvedFlagGeometry::Emblem(
+                        path.clone(),
+                        *size,
+                        *shadow,
+                        Rc::new(field),
+                    )),
+                )
+            }
+            UnresolvedFlagGeometry::Mask(shape, content) => {
+                UnresolvedFlagGeometry::Mask(reverse(shape), reverse(content))
+            }
+            UnresolvedFlagGeometry::Grid(across, down, mode) => {
+                UnresolvedFlagGeometry::Grid(reverse(across), reverse(down), *mode)
+            }
+            UnresolvedFlagGeometry::Fimbriation(thickness, color, geo) => {
+                UnresolvedFlagGeometry::Fimbriation(*thickness, *color, reverse(geo))
+            }
+            UnresolvedFlagGeometry::Alpha(opacity, geo) => {
+                UnresolvedFlagGeometry::Alpha(*opacity, reverse(geo))
+            }
+            UnresolvedFlagGeometry::Flipped(direction, geo) => {
+                UnresolvedFlagGeometry::Flipped(*direction, reverse(geo))
+            }
+            UnresolvedFlagGeometry::Tag(tag, geo) if !keep.contains(&tag.as_str()) => {
+                UnresolvedFlagGeometry::Tag(tag.clone(), reverse(geo))
+            }
+            // Stripes side by side don't line up the same from the other end
+            UnresolvedFlagGeometry::TileFill(Direction::Horizontal, ..) => {
+                UnresolvedFlagGeometry::Flipped(Direction::Horizontal, Rc::new(self.clone()))
+            }
+            // A fragment that can't be read is left for resolving to report
+            UnresolvedFlagGeometry::Include(_) => match self.expand_includes(&mut read_fragment) {
+                Ok(geo) => geo.reverse_side(keep),
+                Err(_) => self.clone(),
+            },
+            UnresolvedFlagGeometry::Solid(_)
+            | UnresolvedFlagGeometry::TileFill(..)
+            | UnresolvedFlagGeometry::Tag(..)
+            | UnresolvedFlagGeometry::Reference(_) => self.clone(),
+        }
+    }
+
+    /// Resolves only the geometry of the tag `name`, as a flag of its own.
+    pub fn resolve_tagged(&self, name: &str) -> Result<FlagGeometry, FlagError> {
+        UnresolvedFlagGeometry::Reference(name.to_string()).resolve(&self.tags())
+    }
+
+    /// Resolves the geometry against its own tags.
+    pub fn resolve_all(&self) -> Result<FlagGeometry, FlagError> {
+        self.resolve(&self.tags())
+    }
+}
+
+/// Rounds a run of ideal sizes in pixels to whole pixels, carrying the
+/// rounding error of each one over to the next, so that every size is within
+/// a pixel of its ideal instead of the error piling up at the end. Sizes are
+/// taken until they cover `length`, and the last one is cut short to fit.
+fn diffuse_lengths(sizes: impl IntoIterator<Item = f32>, length: u32) -> Vec<u32> {
+    let mut lengths = Vec::new();
+    let (mut ideal, mut offset) = (0.0, 0);
+    for size in sizes {
+        if offset >= length {
+            break;
+        }
+        ideal += size;
+        let end = (ideal.round() as u32).clamp(offset, length);
+        lengths.push(end - offset);
+        offset = end;
+    }
+    lengths
+}
+
+/// Divides `length` pixels at `pivot`. A percentage is rounded to the nearest
+/// pixel and the second part takes whatever remains, so the two always cover
+/// the full length. Either part may be empty, as with a pivot of `0`, and
+/// anything beyond the length, such as `150` percent, is clamped to it.
+fn split_length(pivot: Weight, length: u32) -> (u32, u32) {
+    let first = match pivot {
+        Weight::Percent(pivot) => ((pivot * length as f32) / 100.0).round() as u32,
+        Weight::Pixels(pixels) => pixels,
+        Weight::PixelsAfter(pixels) => length.saturating_sub(pixels),
+    };
+    let first = first.min(length);
+    (first, length - first)
+}
+
+/// Lays out a run of splits in the same direction, such as
+/// `(h 25 a (h 50 b c))`, giving each part along with the area it covers.
+/// Percentages are laid out together, with their rounding error spread over
+/// the run by `diffuse_lengths`, so that every part is within a pixel of its
+/// ideal size instead of the error piling up in the last one. A pivot in
+/// pixels ends the run and is laid out on its own by `split_length`.
+fn split_run<'a, T>(
+    geo: &'a T,
+    as_split: impl Fn(&'a T) -> Option<(Direction, &'a T, &'a T, Weight)>,
+    area: Rect,
+) -> Vec<(&'a T, Rect)> {
+    let (direction, car, cdr, pivot) = match as_split(geo) {
+        Some(split) => split,
+        None => return Vec::new(),
+    };
+
+    let length = direction.length(area.width, area.height);
+    let (parts, lengths) = match pivot {
+        Weight::Percent(pivot) => {
+            let (mut parts, mut shares, mut rest) = (vec![car], vec![pivot], cdr);
+            while let Some((_, car, cdr, Weight::Percent(pivot))) =
+                as_split(rest).filter(|(next, ..)| *next == direction)
+            {
+                parts.push(car);
+                shares.push(pivot);
+                rest = cdr;
+            }
+            parts.push(rest);
+
+            let mut remaining = length as f32;
+            let mut sizes = Vec::new();
+            for share in shares {
+                let size = remaining * share.clamp(0.0, 100.0) / 100.0;
+                sizes.push(size);
+                remaining -= size;
+            }
+            sizes.push(remaining);
+            (parts, diffuse_lengths(sizes, length))
+        }
+        _ => {
+            let (first, second) = split_length(pivot, length);
+            (vec![car, cdr], vec![first, second])
+        }
+    };
+
+    let last = parts.len() - 1;
+    let mut offset = 0;
+    let mut rects = Vec::new();
+    for (i, part) in parts.into_iter().enumerate() {
+        // The last part takes whatever is left, including any parts that
+        // `diffuse_lengths` left out once the length was covered
+        let size = if i == last {
+            length - offset
+        } else {
+            lengths.get(i).copied().unwrap_or(0)
+        };
+        let rect = match direction {
+            Direction::Horizontal => Rect::new(area.left + offset, area.top, size, area.height),
+            Direction::Vertical => Rect::new(area.left, area.top + offset, area.width, size),
+        };
+        rects.push((part, rect));
+        offset += size;
+    }
+    rects
+}
+
+/// Cuts the range `from..to`, in percent of the length, out of a split at
+/// `pivot` percent, using `slice` to cut the range out of either side.
+fn slice_split<F>(
+    direction: Direction,
+    car: &FlagGeometry,
+    cdr: &FlagGeometry,
+    pivot: f32,
+    (from, to): (f32, f32),
+    slice: F,
+) -> Option<FlagGeometry>
+where
+    F: Fn(&FlagGeometry, (f32, f32)) -> Option<FlagGeometry>,
+{
+    let pivot = pivot.clamp(0.0, 100.0);
+    let in_car = |x: f32| x * 100.0 / pivot;
+    let in_cdr = |x: f32| (x - pivot) * 100.0 / (100.0 - pivot);
+
+    if to <= pivot {
+        slice(car, (in_car(from), in_car(to)))
+    } else if from >= pivot {
+        slice(cdr, (in_cdr(from), in_cdr(to)))
+    } else {
+        let car = slice(car, (in_car(from), 100.0))?;
+        let cdr = slice(cdr, (0.0, in_cdr(to)))?;
+        let pivot = (pivot - from) * 100.0 / (to - from);
+        Some(FlagGeometry::split(
+            direction,
+            Rc::new(car),
+            Rc::new(cdr),
+            Weight::Percent(pivot),
+        ))
+    }
+}
+
+/// Paints every pixel of an area for which `inside` holds, given coordinates
+/// relative to the area. Consecutive pixels are grouped into horizontal runs
+/// so each run becomes a single `rectangle` call.
+fn fill_spans<P, F>(
+    buffer: &mut P,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+    color: &Color,
+    inside: F,
+) where
+    P: MsPaint,
+    F: Fn(u32, u32) -> bool,
+{
+    for y in 0..height {
+        let mut start = None;
+        for x in 0..=width {
+            match (start, x < width && inside(x, y)) {
+                (None, true) => start = Some(x),
+                (Some(x0), false) => {
+                    buffer.rectangle(left + x0, top + y, x - x0, 1, color);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Paints an area pixel by pixel in the colors given by `color_at`, from
+/// positions relative to the area, leaving the pixels it gives `None` for
+/// untouched. Runs of the same color within a row are painted at once.
+fn paint_runs<P, F>(buffer: &mut P, area: Rect, color_at: F)
+where
+    P: MsPaint,
+    F: Fn(u32, u32) -> Option<Color>,
+{
+    for y in 0..area.height {
+        let mut x = 0;
+        while x < area.width {
+            let color = color_at(x, y);
+            let run = (x..area.width)
+                .take_while(|&x| color_at(x, y) == color)
+                .count() as u32;
+            if let Some(color) = color {
+                buffer.rectangle(area.left + x, area.top + y, run, 1, &color);
+            }
+            x += run;
+        }
+    }
+}
+
+/// Fills a disc centered at (`cx`, `cy`), relative to the area, one row at a
+/// time using the span given by the circle equation.
+#[allow(clippy::too_many_arguments)]
+fn fill_disc<P: MsPaint>(
+    buffer: &mut P,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+    cx: f32,
+    cy: f32,
+    radius: f32,
+    color: &Color,
+) {
+    let area = Rect::new(left, top, width, height);
+    for y in 0..height {
+        let dy = y as f32 + 0.5 - cy;
+        if dy.abs() >= radius {
+            continue;
+        }
+
+        let half = (radius * radius - dy * dy).sqrt();
+        let x0 = (cx - half).round() as i64;
+        let x1 = (cx + half).round() as i64;
+        let span = clamp_rect(
+            left as i64 + x0,
+            (top + y) as i64,
+            (x1 - x0).max(0) as u32,
+            1,
+            area,
+        );
+        if !span.is_empty() {
+            buffer.rectangle(span.left, span.top, span.width, 1, color);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlagGeometry {
+    Solid(Color),
+    Horizontal(Rc<FlagGeometry>, Rc<FlagGeometry>, Weight),
+    Vertical(Rc<FlagGeometry>, Rc<FlagGeometry>, Weight),
+    /// `count` rays radiating from the center over a field, with a disc of
+    /// the center color covering the point they meet at.
+    Rays(u32, Color, Color, Rc<FlagGeometry>),
+    /// A disc centered at percentages of the width and height, with a radius
+    /// given as a percentage of the shorter side, drawn over a field.
+    Disc(f32, f32, f32, Color, Rc<FlagGeometry>),
+    /// Alternating stripes of two colors, each a percentage of the length
+    /// in the given direction thick, repeated until the area is filled.
+    TileFill(Direction, f32, Color, Color),
+    /// An image centered over a field, scaled to fit within a percentage of
+    /// the width and height while keeping its proportions.
+    Emblem(Rc<RgbaImage>, f32, Option<Shadow>, Rc<FlagGeometry>),
+    /// The second geometry, but only where the first one paints anything.
+    Mask(Rc<FlagGeometry>, Rc<FlagGeometry>),
+    /// An area colored pixel by pixel by a function, such as for noise or
+    /// patterns. These can only be built in code, not written in FDL.
+    Procedural(Fill),
+    /// A geometry drawn with its opacity scaled by a factor between 0 and 1.
+    Alpha(f32, Rc<FlagGeometry>),
+    /// Two layers of stripes drawn across each other, such as for a tartan,
+    /// with their colors combined where both paint.
+    Grid(Rc<FlagGeometry>, Rc<FlagGeometry>, BlendMode),
+    /// A geometry drawn mirrored, left to right for `Horizontal` and top to
+    /// bottom for `Vertical`, for the parts that can't be mirrored by
+    /// rearranging them.
+    Flipped(Direction, Rc<FlagGeometry>),
+}
+
+/// A function giving the color of each pixel of an area, from its position
+/// relative to the top left corner of the area. Two fills are only equal if
+/// they are the same function.
+#[derive(Clone)]
+pub struct Fill(pub Rc<dyn Fn(u32, u32) -> Rgb<u8>>);
+
+impl std::fmt::Debug for Fill {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Fill")
+    }
+}
+
+impl PartialEq for Fill {
+    fn eq(&self, other: &Fill) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The symmetries of a flag, as found by `FlagGeometry::symmetry`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symmetry {
+    /// Mirrored about the horizontal axis, so that it's the same upside down.
+    pub horizontal: bool,
+    /// Mirrored about the vertical axis, so that the reverse side is the same.
+    pub vertical: bool,
+    /// The same when turned half a turn.
+    pub rotational: bool,
+}
+
+/// A plain white flag.
+impl Default for FlagGeometry {
+    fn default() -> Self {
+        FlagGeometry::Solid(Color::default())
+    }
+}
+
+/// A shadow cast by an emblem onto the field beneath it, in the shape of the
+/// opaque parts of the emblem.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Shadow {
+    /// How many pixels to the right of the emblem the shadow falls.
+    pub dx: i32,
+    /// How many pixels below the emblem the shadow falls.
+    pub dy: i32,
+    pub color: Color,
+}
+
+impl FlagGeometry {
+    /// An area colored by calling `fill` for each pixel.
+    pub fn procedural(fill: impl Fn(u32, u32) -> Rgb<u8> + 'static) -> FlagGeometry {
+        FlagGeometry::Procedural(Fill(Rc::new(fill)))
+    }
+
+    pub fn split(
+        direction: Direction,
+        car: Rc<FlagGeometry>,
+        cdr: Rc<FlagGeometry>,
+        pivot: Weight,
+    ) -> FlagGeometry {
+        match direction {
+            Direction::Horizontal => FlagGeometry::Horizontal(car, cdr, pivot),
+            Direction::Vertical => FlagGeometry::Vertical(car, cdr, pivot),
+        }
+    }
+
+    /// The direction, parts and pivot of a split, or `None` for anything else.
+    fn as_split(&self) -> Option<(Direction, &FlagGeometry, &FlagGeometry, Weight)> {
+        match self {
+            FlagGeometry::Horizontal(car, cdr, pivot) => {
+                Some((Direction::Horizontal, car.as_ref(), cdr.as_ref(), *pivot))
+            }
+            FlagGeometry::Vertical(car, cdr, pivot) => {
+                Some((Direction::Vertical, car.as_ref(), cdr.as_ref(), *pivot))
+            }
+            _ => None,
+        }
+    }
+
+    fn draw_area<P: MsPaint>(&self, buffer: &mut P, left: u32, top: u32, width: u32, height: u32) {
+        match self {
+            FlagGeometry::Solid(color) => {
+                buffer.rectangle(left, top, width, height, color);
+            }
+            FlagGeometry::Horizontal(..) | FlagGeometry::Vertical(..) => {
+                let area = Rect::new(left, top, width, height);
+                for (part, rect) in split_run(self, FlagGeometry::as_split, area) {
+                    part.draw_area(buffer, rect.left, rect.top, rect.width, rect.height);
+                }
+            }
+            FlagGeometry::Rays(count, center, ray, field) => {
+                field.draw_area(buffer, left, top, width, height);
+
+                let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+                let sector = std::f32::consts::PI / (*count).max(1) as f32;
+                fill_spans(buffer, left, top, width, height, ray, |x, y| {
+                    let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                    let angle = dy.atan2(dx).rem_euclid(2.0 * std::f32::consts::PI);
+                    ((angle / sector) as u32).is_multiple_of(2)
+                });
+
+                let radius = width.min(height) as f32 / 5.0;
+                fill_disc(buffer, left, top, width, height, cx, cy, radius, center);
+            }
+            FlagGeometry::Disc(x, y, radius, color, field) => {
+                field.draw_area(buffer, left, top, width, height);
+
+                let cx = x * width as f32 / 100.0;
+                let cy = y * height as f32 / 100.0;
+                let radius = radius * width.min(height) as f32 / 100.0;
+                fill_disc(buffer, left, top, width, height, cx, cy, radius, color);
+            }
+            FlagGeometry::TileFill(direction, stripe, a, b) => {
+                let length = direction.length(width, height);
+                let stripe = (stripe * length as f32 / 100.0).max(1.0);
+                let sizes = diffuse_lengths(std::iter::repeat(stripe), length);
+
+                let mut offset = 0;
+                for (i, size) in sizes.into_iter().enumerate() {
+                    let color = if i.is_multiple_of(2) { a } else { b };
+                    match direction {
+                        Direction::Horizontal => {
+                            buffer.rectangle(left + offset, top, size, height, color)
+                        }
+                        Direction::Vertical => {
+                            buffer.rectangle(left, top + offset, width, size, color)
+                        }
+                    }
+                    offset += size;
+                }
+            }
+            FlagGeometry::Procedural(Fill(fill)) => {
+                let area = Rect::new(left, top, width, height);
+                paint_runs(buffer, area, |x, y| Some(Color::Rgb(fill(x, y).0)));
+            }
+            FlagGeometry::Mask(..)
+            | FlagGeometry::Grid(..)
+            | FlagGeometry::Alpha(..)
+            | FlagGeometry::Flipped(..) => {
+                let area = Rect::new(left, top, width, height);
+                self.draw_layers(buffer, area, Rect::new(0, 0, width, height));
+            }
+            FlagGeometry::Emblem(img, size, shadow, field) => {
+                field.draw_area(buffer, left, top, width, height);
+                if img.width() == 0 || img.height() == 0 {
+                    return;
+                }
+
+                let scale = (size * width as f32 / 100.0 / img.width() as f32)
+                    .min(size * height as f32 / 100.0 / img.height() as f32);
+                let w = ((img.width() as f32 * scale).round() as u32).min(width);
+                let h = ((img.height() as f32 * scale).round() as u32).min(height);
+                let (x0, y0) = ((width - w) / 2, (height - h) / 2);
+                let opaque = |x: u32, y: u32| {
+                    let pixel = img[(x * img.width() / w, y * img.height() / h)];
+                    Some(pixel).filter(|pixel| pixel.0[3] > 0)
+                };
+
+                // The shadow goes underneath, and never outside of the area
+                let area = Rect::new(left, top, width, height);
+                let (ex, ey) = ((left + x0) as i64, (top + y0) as i64);
+                if let Some(Shadow { dx, dy, color }) = shadow {
+                    let (sx, sy) = (ex + *dx as i64, ey + *dy as i64);
+                    let cast = clamp_rect(sx, sy, w, h, area);
+                    for py in cast.top..cast.bottom() {
+                        for px in cast.left..cast.right() {
+                            let (x, y) = ((px as i64 - sx) as u32, (py as i64 - sy) as u32);
+                            if opaque(x, y).is_some() {
+                                buffer.rectangle(px, py, 1, 1, color);
+                            }
+                        }
+                    }
+                }
+
+                let placed = clamp_rect(ex, ey, w, h, area);
+                for py in placed.top..placed.bottom() {
+                    for px in placed.left..placed.right() {
+                        let (x, y) = ((px as i64 - ex) as u32, (py as i64 - ey) as u32);
+                        if let Some(pixel) = opaque(x, y) {
+                            buffer.rectangle(px, py, 1, 1, &Color::Rgba(pixel.0));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Flips the flag left to right. Emblems keep their orientation, while
+    /// everything laid out around them is flipped. Procedural fills, side by
+    /// side tile fills and even numbers of rays are wrapped so that they are
+    /// drawn mirrored.
+    pub fn mirror_horizontal(&self) -> FlagGeometry {
+        match self {
+            FlagGeometry::Horizontal(car, cdr, pivot) => FlagGeometry::Horizontal(
+                Rc::new(cdr.mirror_horizontal()),
+                Rc::new(car.mirror_horizontal()),
+                pivot.mirrored(),
+            ),
+            FlagGeometry::Vertical(car, cdr, pivot) => FlagGeometry::Vertical(
+                Rc::new(car.mirror_horizontal()),
+                Rc::new(cdr.mirror_horizontal()),
+                *pivot,
+            ),
+            // An odd number of rays has one pointing straight left and one
+            // straight right, so it lines up the same from either side
+            FlagGeometry::Rays(count, center, ray, field) if !count.is_multiple_of(2) => {
+                FlagGeometry::Rays(*count, *center, *ray, Rc::new(field.mirror_horizontal()))
+            }
+            FlagGeometry::Disc(x, y, radius, color, field) => FlagGeometry::Disc(
+                100.0 - x,
+                *y,
+                *radius,
+                *color,
+                Rc::new(field.mirror_horizontal()),
+            ),
+            FlagGeometry::Emblem(img, size, shadow, field) => FlagGeometry::Emblem(
+                img.clone(),
+                *size,
+                *shadow,
+                Rc::new(field.mirror_horizontal()),
+            ),
+            FlagGeometry::Mask(shape, content) => FlagGeometry::Mask(
+                Rc::new(shape.mirror_horizontal()),
+                Rc::new(content.mirror_horizontal()),
+            ),
+            FlagGeometry::Grid(across, down, mode) => FlagGeometry::Grid(
+                Rc::new(across.mirror_horizontal()),
+                Rc::new(down.mirror_horizontal()),
+                *mode,
+            ),
+            FlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, Rc::new(geo.mirror_horizontal()))
+            }
+            FlagGeometry::Flipped(Direction::Horizontal, _)
+            | FlagGeometry::TileFill(Direction::Horizontal, ..)
+            | FlagGeometry::Rays(..)
+            | FlagGeometry::Procedural(_) => self.flipped(Direction::Horizontal),
+            FlagGeometry::Flipped(direction, geo) => geo.half_turned_rays().unwrap_or_else(|| {
+                FlagGeometry::Flipped(*direction, Rc::new(geo.mirror_horizontal()))
+            }),
+            // Stripes running the way of the flip are the same either way
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) => self.clone(),
+        }
+    }
+
+    /// Flips the flag top to bottom. Emblems keep their orientation, while
+    /// everything laid out around them is flipped. Procedural fills, stacked
+    /// tile fills and rays are wrapped so that they are drawn mirrored.
+    pub fn mirror_vertical(&self) -> FlagGeometry {
+        match self {
+            FlagGeometry::Horizontal(car, cdr, pivot) => FlagGeometry::Horizontal(
+                Rc::new(car.mirror_vertical()),
+                Rc::new(cdr.mirror_vertical()),
+                *pivot,
+            ),
+            FlagGeometry::Vertical(car, cdr, pivot) => FlagGeometry::Vertical(
+                Rc::new(cdr.mirror_vertical()),
+                Rc::new(car.mirror_vertical()),
+                pivot.mirrored(),
+            ),
+            FlagGeometry::Disc(x, y, radius, color, field) => FlagGeometry::Disc(
+                *x,
+                100.0 - y,
+                *radius,
+                *color,
+                Rc::new(field.mirror_vertical()),
+            ),
+            FlagGeometry::Emblem(img, size, shadow, field) => FlagGeometry::Emblem(
+                img.clone(),
+                *size,
+                *shadow,
+                Rc::new(field.mirror_vertical()),
+            ),
+            FlagGeometry::Mask(shape, content) => FlagGeometry::Mask(
+                Rc::new(shape.mirror_vertical()),
+                Rc::new(content.mirror_vertical()),
+            ),
+            FlagGeometry::Grid(across, down, mode) => FlagGeometry::Grid(
+                Rc::new(across.mirror_vertical()),
+                Rc::new(down.mirror_vertical()),
+                *mode,
+            ),
+            FlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, Rc::new(geo.mirror_vertical()))
+            }
+            FlagGeometry::Flipped(Direction::Vertical, _)
+            | FlagGeometry::TileFill(Direction::Vertical, ..)
+            | FlagGeometry::Rays(..)
+            | FlagGeometry::Procedural(_) => self.flipped(Direction::Vertical),
+            FlagGeometry::Flipped(direction, geo) => geo.half_turned_rays().unwrap_or_else(|| {
+                FlagGeometry::Flipped(*direction, Rc::new(geo.mirror_vertical()))
+            }),
+            // Stripes running the way of the flip are the same either way
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) => self.clone(),
+        }
+    }
+
+    /// Cuts out part of the flag as a flag of its own. The part is given in
+    /// percent of the width and height, like the position of a disc. Splits
+    /// are cut down to what is visible, while rays, discs, tile fills and
+    /// splits measured in pixels can only be kept whole, so a slice through
+    /// one of them gives `None`.
+    pub fn slice(&self, left: f32, top: f32, width: f32, height: f32) -> Option<FlagGeometry> {
+        self.slice_bounds((left, left + width), (top, top + height))
+    }
+
+    fn slice_bounds(&self, xs: (f32, f32), ys: (f32, f32)) -> Option<FlagGeometry> {
+        match self {
+            FlagGeometry::Solid(color) => Some(FlagGeometry::Solid(*color)),
+            FlagGeometry::Horizontal(car, cdr, Weight::Percent(pivot)) => {
+                slice_split(Direction::Horizontal, car, cdr, *pivot, xs, |geo, xs| {
+                    geo.slice_bounds(xs, ys)
+                })
+            }
+            FlagGeometry::Vertical(car, cdr, Weight::Percent(pivot)) => {
+                slice_split(Direction::Vertical, car, cdr, *pivot, ys, |geo, ys| {
+                    geo.slice_bounds(xs, ys)
+                })
+            }
+            _ if xs.0 <= 0.0 && ys.0 <= 0.0 && xs.1 >= 100.0 && ys.1 >= 100.0 => Some(self.clone()),
+            _ => None,
+        }
+    }
+
+    /// Writes the flag back out as FDL, which parses to the same geometry.
+    /// Emblems and procedural fills have nothing to write them as, and
+    /// neither do colors with transparency, so those give `None`.
+    pub fn to_fdl(&self) -> Option<String> {
+        let weight = |weight: &Weight| match weight {
+            Weight::Percent(pivot) => pivot.to_string(),
+            Weight::Pixels(pixels) => format!("{}px", pixels),
+            Weight::PixelsAfter(pixels) => format!("-{}px", pixels),
+        };
+        Some(match self {
+            FlagGeometry::Solid(color) => format!("(s {})", color.to_fdl()?),
+            FlagGeometry::Horizontal(car, cdr, pivot) => {
+                format!("(h {} {} {})", weight(pivot), car.to_fdl()?, cdr.to_fdl()?)
+            }
+            FlagGeometry::Vertical(car, cdr, pivot) => {
+                format!("(v {} {} {})", weight(pivot), car.to_fdl()?, cdr.to_fdl()?)
+            }
+            FlagGeometry::Rays(count, center, ray, field) => format!(
+                "(rays {} {} {} {})",
+                count,
+                center.to_fdl()?,
+                ray.to_fdl()?,
+                field.to_fdl()?
+            ),
+            FlagGeometry::Disc(x, y, radius, color, field) => format!(
+                "(disc {} {} {} {} {})",
+                x,
+                y,
+                radius,
+                color.to_fdl()?,
+                field.to_fdl()?
+            ),
+            FlagGeometry::TileFill(direction, stripe, a, b) => {
+                let direction = match direction {
+                    Direction::Horizontal => "h",
+                    Direction::Vertical => "v",
+                };
+                format!(
+                    "(tilefill {} {} {} {})",
+                    direction,
+                    stripe,
+                    a.to_fdl()?,
+                    b.to_fdl()?
+                )
+            }
+            FlagGeometry::Mask(shape, content) => {
+                format!("(mask {} {})", shape.to_fdl()?, content.to_fdl()?)
+            }
+            FlagGeometry::Grid(across, down, mode) => {
+                let mode = match mode {
+                    BlendMode::Multiply => "multiply",
+                    BlendMode::Mix => "mix",
+                };
+                format!("(grid {} {} {})", across.to_fdl()?, down.to_fdl()?, mode)
+            }
+            FlagGeometry::Alpha(opacity, geo) => format!("(alpha {} {})", opacity, geo.to_fdl()?),
+            FlagGeometry::Emblem(..) | FlagGeometry::Procedural(_) | FlagGeometry::Flipped(..) => {
+                return None
+            }
+        })
+    }
+
+    /// The same flag with the parts that make no difference to how it looks
+    /// taken out, such as splits between two parts of the same color, splits
+    /// that give one part everything and fully opaque `alpha`.
+    pub fn simplify(&self) -> FlagGeometry {
+        match self {
+            FlagGeometry::Horizontal(car, cdr, pivot) | FlagGeometry::Vertical(car, cdr, pivot) => {
+                let direction = match self {
+                    FlagGeometry::Horizontal(..) => Direction::Horizontal,
+                    _ => Direction::Vertical,
+                };
+                let (car, cdr) = (car.simplify(), cdr.simplify());
+                match (pivot, &car) {
+                    (Weight::Percent(pivot), _) if *pivot <= 0.0 => cdr,
+                    (Weight::Percent(pivot), _) if *pivot >= 100.0 => car,
+                    (_, FlagGeometry::Solid(_)) if car == cdr => car,
+                    _ => FlagGeometry::split(direction, Rc::new(car), Rc::new(cdr), *pivot),
+                }
+            }
+            FlagGeometry::Rays(count, center, ray, field) => match field.simplify() {
+                FlagGeometry::Solid(color) if color == *center && color == *ray => {
+                    FlagGeometry::Solid(color)
+                }
+                field => FlagGeometry::Rays(*count, *center, *ray, Rc::new(field)),
+            },
+            FlagGeometry::Disc(x, y, radius, color, field) => match field.simplify() {
+                FlagGeometry::Solid(field) if field == *color => FlagGeometry::Solid(field),
+                field => FlagGeometry::Disc(*x, *y, *radius, *color, Rc::new(field)),
+            },
+            FlagGeometry::TileFill(_, _, a, b) if a == b => FlagGeometry::Solid(*a),
+            FlagGeometry::Emblem(image, scale, shadow, field) => {
+                FlagGeometry::Emblem(image.clone(), *scale, *shadow, Rc::new(field.simplify()))
+            }
+            FlagGeometry::Mask(shape, content) => {
+                FlagGeometry::Mask(Rc::new(shape.simplify()), Rc::new(content.simplify()))
+            }
+            FlagGeometry::Grid(across, down, mode) => {
+                FlagGeometry::Grid(Rc::new(across.simplify()), Rc::new(down.simplify()), *mode)
+            }
+            FlagGeometry::Alpha(opacity, geo) if *opacity >= 1.0 => geo.simplify(),
+            FlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, Rc::new(geo.simplify()))
+            }
+            FlagGeometry::Flipped(direction, geo) => match geo.simplify() {
+                FlagGeometry::Solid(color) => FlagGeometry::Solid(color),
+                geo => FlagGeometry::Flipped(*direction, Rc::new(geo)),
+            },
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) | FlagGeometry::Procedural(_) => {
+                self.clone()
+            }
+        }
+    }
+
+    /// The shortest FDL for the flag that we know how to write, for sharing
+    /// flags in places where space is scarce. The flag is simplified first,
+    /// colors are written as their one letter code where there is one and
+    /// spaces are left out wherever the parser can do without them. It gives
+    /// `None` in the same cases as `to_fdl`.
+    pub fn to_minimal_fdl(&self) -> Option<String> {
+        let fdl = self.simplify().to_fdl()?;
+        let sexpr = SExpr::parse(&mut fdl.chars().peekable())?;
+        let mut minimal = String::new();
+        write_minimal(&sexpr, &mut minimal);
+        Some(minimal)
+    }
+
+    /// The geometry drawn mirrored in the given direction, unwrapping it
+    /// instead if it is already mirrored that way, so that flipping twice
+    /// gives back the same geometry.
+    fn flipped(&self, direction: Direction) -> FlagGeometry {
+        match self {
+            FlagGeometry::Flipped(flip, geo) if *flip == direction => (**geo).clone(),
+            _ => FlagGeometry::Flipped(direction, Rc::new(self.clone())),
+        }
+    }
+
+    /// The geometry turned half way round if it is an even number of rays,
+    /// which look the same that way so that only their field is turned, for
+    /// rays that were flipped one way and are then flipped the other.
+    fn half_turned_rays(&self) -> Option<FlagGeometry> {
+        match self {
+            FlagGeometry::Rays(count, center, ray, field) if count.is_multiple_of(2) => {
+                let field = field.mirror_vertical().mirror_horizontal();
+                Some(FlagGeometry::Rays(*count, *center, *ray, Rc::new(field)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Which ways the flag can be flipped or turned without changing its
+    /// structure. This only compares the geometry with its mirror images, so
+    /// two layouts that look the same but are split differently count as
+    /// different, and emblems, which keep their orientation when mirrored,
+    /// never break symmetry. Procedural fills, and tile fills flipped across
+    /// their stripes, can't be compared with their mirror images, so they
+    /// always break it.
+    pub fn symmetry(&self) -> Symmetry {
+        let horizontal = self.mirror_vertical();
+        Symmetry {
+            horizontal: horizontal == *self,
+            vertical: self.mirror_horizontal() == *self,
+            rotational: horizontal.mirror_horizontal() == *self,
+        }
+    }
+
+    /// The same flag with a band of `color`, `thickness` pixels thick, centered
+    /// on the line between the parts of a split. The parts of a split that
+    /// are split the same way get bands between them too, so every stripe of
+    /// a tricolor is set off. Anything other than a split is left as is.
+    pub fn fimbriated(&self, thickness: u32, color: &Color) -> FlagGeometry {
+        let (direction, car, cdr, pivot) = match self {
+            FlagGeometry::Horizontal(car, cdr, pivot) => (Direction::Horizontal, car, cdr, pivot),
+            FlagGeometry::Vertical(car, cdr, pivot) => (Direction::Vertical, car, cdr, pivot),
+            _ => return self.clone(),
+        };
+        if thickness == 0 {
+            return self.clone();
+        }
+
+        let fimbriated = |part: &FlagGeometry| match (direction, part) {
+            (Direction::Horizontal, FlagGeometry::Horizontal(..))
+            | (Direction::Vertical, FlagGeometry::Vertical(..)) => {
+                Rc::new(part.fimbriated(thickness, color))
+            }
+            _ => Rc::new(part.clone()),
+        };
+
+        // Each part gives up half of the band, so the line between them stays
+        // where it was
+        let band = Rc::new(FlagGeometry::Solid(*color));
+        let car = FlagGeometry::split(
+            direction,
+            fimbriated(car),
+            band.clone(),
+            Weight::PixelsAfter(thickness / 2),
+        );
+        let cdr = FlagGeometry::split(
+            direction,
+            band,
+            fimbriated(cdr),
+            Weight::Pixels(thickness - thickness / 2),
+        );
+        FlagGeometry::split(direction, Rc::new(car), Rc::new(cdr), *pivot)
+    }
+
+    /// The same flag with every color multiplied by `tint`.
+    pub fn tinted(&self, tint: &Color) -> FlagGeometry {
+        match self {
+            FlagGeometry::Solid(color) => FlagGeometry::Solid(color.multiply(tint)),
+            FlagGeometry::Horizontal(car, cdr, pivot) => FlagGeometry::Horizontal(
+                Rc::new(car.tinted(tint)),
+                Rc::new(cdr.tinted(tint)),
+                *pivot,
+            ),
+            FlagGeometry::Vertical(car, cdr, pivot) => {
+                FlagGeometry::Vertical(Rc::new(car.tinted(tint)), Rc::new(cdr.tinted(tint)), *pivot)
+            }
+            FlagGeometry::Rays(count, center, ray, field) => FlagGeometry::Rays(
+                *count,
+                center.multiply(tint),
+                ray.multiply(tint),
+                Rc::new(field.tinted(tint)),
+            ),
+            FlagGeometry::Disc(x, y, radius, color, field) => FlagGeometry::Disc(
+                *x,
+                *y,
+                *radius,
+                color.multiply(tint),
+                Rc::new(field.tinted(tint)),
+            ),
+            FlagGeometry::TileFill(direction, stripe, a, b) => {
+                FlagGeometry::TileFill(*direction, *stripe, a.multiply(tint), b.multiply(tint))
+            }
+            FlagGeometry::Emblem(img, size, shadow, field) => {
+                let mut img = (**img).clone();
+                for pixel in img.pixels_mut() {
+                    if let Color::Rgba(rgba) = Color::Rgba(pixel.0).multiply(tint) {
+                        *pixel = rgba.into();
+                    }
+                }
+                let shadow = shadow.map(|shadow| Shadow {
+                    color: shadow.color.multiply(tint),
+                    ..shadow
+                });
+                FlagGeometry::Emblem(Rc::new(img), *size, shadow, Rc::new(field.tinted(tint)))
+            }
+            FlagGeometry::Mask(shape, content) => {
+                FlagGeometry::Mask(shape.clone(), Rc::new(content.tinted(tint)))
+            }
+            FlagGeometry::Grid(across, down, mode) => FlagGeometry::Grid(
+                Rc::new(across.tinted(tint)),
+                Rc::new(down.tinted(tint)),
+                *mode,
+            ),
+            FlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, Rc::new(geo.tinted(tint)))
+            }
+            FlagGeometry::Flipped(direction, geo) => {
+                FlagGeometry::Flipped(*direction, Rc::new(geo.tinted(tint)))
+            }
+            FlagGeometry::Procedural(Fill(fill)) => {
+                let (fill, tint) = (fill.clone(), *tint);
+                FlagGeometry::procedural(move |x, y| {
+                    match Color::Rgb(fill(x, y).0).multiply(&tint) {
+                        Color::Rgba([r, g, b, _]) => Rgb([r, g, b]),
+                        color => color.to_rgb(),
+                    }
+                })
+            }
+        }
+    }
+
+    /// The same flag with every color snapped to the nearest known color, as
+    /// by `Color::nearest_named`. Emblems and procedural fills are pictures
+    /// rather than flag colors, so they are left as they are.
+    pub fn quantize_to_palette(&self) -> FlagGeometry {
+        match self {
+            FlagGeometry::Solid(color) => FlagGeometry::Solid(color.nearest_named()),
+            FlagGeometry::Horizontal(car, cdr, pivot) => FlagGeometry::Horizontal(
+                Rc::new(car.quantize_to_palette()),
+                Rc::new(cdr.quantize_to_palette()),
+                *pivot,
+            ),
+            FlagGeometry::Vertical(car, cdr, pivot) => FlagGeometry::Vertical(
+                Rc::new(car.quantize_to_palette()),
+                Rc::new(cdr.quantize_to_palette()),
+                *pivot,
+            ),
+            FlagGeometry::Rays(count, center, ray, field) => FlagGeometry::Rays(
+                *count,
+                center.nearest_named(),
+                ray.nearest_named(),
+                Rc::new(field.quantize_to_palette()),
+            ),
+            FlagGeometry::Disc(x, y, radius, color, field) => FlagGeometry::Disc(
+                *x,
+                *y,
+                *radius,
+                color.nearest_named(),
+                Rc::new(field.quantize_to_palette()),
+            ),
+            FlagGeometry::TileFill(direction, stripe, a, b) => {
+                FlagGeometry::TileFill(*direction, *stripe, a.nearest_named(), b.nearest_named())
+            }
+            FlagGeometry::Emblem(img, size, shadow, field) => FlagGeometry::Emblem(
+                img.clone(),
+                *size,
+                *shadow,
+                Rc::new(field.quantize_to_palette()),
+            ),
+            FlagGeometry::Mask(shape, content) => {
+                FlagGeometry::Mask(shape.clone(), Rc::new(content.quantize_to_palette()))
+            }
+            FlagGeometry::Grid(across, down, mode) => FlagGeometry::Grid(
+                Rc::new(across.quantize_to_palette()),
+                Rc::new(down.quantize_to_palette()),
+                *mode,
+            ),
+            FlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, Rc::new(geo.quantize_to_palette()))
+            }
+            FlagGeometry::Flipped(direction, geo) => {
+                FlagGeometry::Flipped(*direction, Rc::new(geo.quantize_to_palette()))
+            }
+            FlagGeometry::Procedural(_) => self.clone(),
+        }
+    }
+
+    /// The distinct colors used by the flag, in the order they are first
+    /// drawn. The pixels of emblems are images rather than flag colors, so
+    /// they aren't included.
+    pub fn colors(&self) -> Vec<Color> {
+        let mut colors = Vec::new();
+        self.collect_colors(&mut colors);
+        colors
+    }
+
+    /// The colors of the flag as a GIMP palette, the `.gpl` files that GIMP,
+    /// Inkscape and Krita can import, titled `name`. Each color is listed once
+    /// by its RGB value, labelled with how it is written in FDL, and
+    /// transparency is left out.
+    pub fn to_gimp_palette(&self, name: &str) -> String {
+        let mut palette = format!("GIMP Palette\nName: {}\nColumns: 0\n#\n", name);
+        let mut seen = Vec::new();
+        for color in self.colors() {
+            let rgb @ Rgb([r, g, b]) = color.to_rgb();
+            if color == Color::None || seen.contains(&rgb) {
+                continue;
+            }
+            seen.push(rgb);
+            let label = color
+                .to_fdl()
+                .unwrap_or_else(|| format!("#{:02x}{:02x}{:02x}", r, g, b));
+            palette.push_str(&format!("{:3} {:3} {:3}\t{}\n", r, g, b, label));
+        }
+        palette
+    }
+
+    fn collect_colors(&self, colors: &mut Vec<Color>) {
+        fn add(colors: &mut Vec<Color>, color: &Color) {
+            if !colors.contains(color) {
+                colors.push(*color);
+            }
+        }
+
+        match self {
+            FlagGeometry::Solid(color) => add(colors, color),
+            FlagGeometry::Horizontal(car, cdr, _) | FlagGeometry::Vertical(car, cdr, _) => {
+                car.collect_colors(colors);
+                cdr.collect_colors(colors);
+            }
+            FlagGeometry::Rays(_, center, ray, field) => {
+                field.collect_colors(colors);
+                add(colors, ray);
+                add(colors, center);
+            }
+            FlagGeometry::Disc(.., color, field) => {
+                field.collect_colors(colors);
+                add(colors, color);
+            }
+            FlagGeometry::TileFill(_, _, a, b) => {
+                add(colors, a);
+                add(colors, b);
+            }
+            FlagGeometry::Emblem(.., field) => field.collect_colors(colors),
+            FlagGeometry::Mask(_, content)
+            | FlagGeometry::Alpha(_, content)
+            | FlagGeometry::Flipped(_, content) => content.collect_colors(colors),
+            FlagGeometry::Grid(across, down, _) => {
+                across.collect_colors(colors);
+                down.collect_colors(colors);
+            }
+            // There is no telling which colors a function gives
+            FlagGeometry::Procedural(_) => {}
+        }
+    }
+
+    /// The number of levels in the geometry tree, counting a lone solid as one.
+    pub fn depth(&self) -> usize {
+        match self {
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) | FlagGeometry::Procedural(_) => 1,
+            FlagGeometry::Horizontal(car, cdr, _)
+            | FlagGeometry::Vertical(car, cdr, _)
+            | FlagGeometry::Mask(car, cdr)
+            | FlagGeometry::Grid(car, cdr, _) => 1 + car.depth().max(cdr.depth()),
+            FlagGeometry::Rays(.., field)
+            | FlagGeometry::Disc(.., field)
+            | FlagGeometry::Emblem(.., field)
+            | FlagGeometry::Alpha(_, field)
+            | FlagGeometry::Flipped(_, field) => 1 + field.depth(),
+        }
+    }
+
+    /// The total number of nodes in the geometry tree.
+    pub fn element_count(&self) -> usize {
+        match self {
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) | FlagGeometry::Procedural(_) => 1,
+            FlagGeometry::Horizontal(car, cdr, _)
+            | FlagGeometry::Vertical(car, cdr, _)
+            | FlagGeometry::Mask(car, cdr)
+            | FlagGeometry::Grid(car, cdr, _) => 1 + car.element_count() + cdr.element_count(),
+            FlagGeometry::Rays(.., field)
+            | FlagGeometry::Disc(.., field)
+            | FlagGeometry::Emblem(.., field)
+            | FlagGeometry::Alpha(_, field)
+            | FlagGeometry::Flipped(_, field) => 1 + field.element_count(),
+        }
+    }
+
+    /// The smallest width and height at which every part of every split can
+    /// get at least one pixel.
+    pub fn minimum_size(&self) -> (u32, u32) {
+        match self {
+            FlagGeometry::Horizontal(car, cdr, _) => {
+                let ((car_w, car_h), (cdr_w, cdr_h)) = (car.minimum_size(), cdr.minimum_size());
+                (car_w + cdr_w, car_h.max(cdr_h))
+            }
+            FlagGeometry::Vertical(car, cdr, _) => {
+                let ((car_w, car_h), (cdr_w, cdr_h)) = (car.minimum_size(), cdr.minimum_size());
+                (car_w.max(cdr_w), car_h + cdr_h)
+            }
+            FlagGeometry::Mask(a, b) | FlagGeometry::Grid(a, b, _) => {
+                let ((a_w, a_h), (b_w, b_h)) = (a.minimum_size(), b.minimum_size());
+                (a_w.max(b_w), a_h.max(b_h))
+            }
+            FlagGeometry::Rays(.., field)
+            | FlagGeometry::Disc(.., field)
+            | FlagGeometry::Emblem(.., field)
+            | FlagGeometry::Alpha(_, field)
+            | FlagGeometry::Flipped(_, field) => field.minimum_size(),
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) | FlagGeometry::Procedural(_) => {
+                (1, 1)
+            }
+        }
+    }
+
+    /// The same flag with every split fixed in pixels for an area of the
+    /// given size, moved where needed so that each part gets at least its
+    /// minimum size, taken from the other part. The area has to be at least
+    /// the minimum size of the flag.
+    fn with_minimum_sizes(&self, width: u32, height: u32) -> FlagGeometry {
+        match self {
+            FlagGeometry::Horizontal(..) | FlagGeometry::Vertical(..) => {
+                let direction = match self {
+                    FlagGeometry::Horizontal(..) => Direction::Horizontal,
+                    _ => Direction::Vertical,
+                };
+
+                // The parts are laid out as they would be drawn, and then each
+                // one is given at least its minimum, taken from those after it
+                let parts = split_run(self, FlagGeometry::as_split, Rect::new(0, 0, width, height));
+                let minimums = parts
+                    .iter()
+                    .map(|(part, _)| {
+                        let (w, h) = part.minimum_size();
+                        direction.length(w, h)
+                    })
+                    .collect::<Vec<_>>();
+                let mut remaining = direction.length(width, height);
+                let mut sizes = Vec::new();
+                for (i, (_, rect)) in parts.iter().enumerate() {
+                    let size = if i == parts.len() - 1 {
+                        remaining
+                    } else {
+                        let after = minimums[i + 1..].iter().sum::<u32>();
+                        direction
+                            .length(rect.width, rect.height)
+                            .clamp(minimums[i], remaining - after)
+                    };
+                    sizes.push(size);
+                    remaining -= size;
+                }
+
+                let sized = |part: &FlagGeometry, size| match direction {
+                    Direction::Horizontal => part.with_minimum_sizes(size, height),
+                    Direction::Vertical => part.with_minimum_sizes(width, size),
+                };
+                parts
+                    .iter()
+                    .zip(sizes)
+                    .rev()
+                    .fold(None, |rest, ((part, _), size)| {
+                        let part = Rc::new(sized(part, size));
+                        Some(match rest {
+                            Some(rest) => FlagGeometry::split(
+                                direction,
+                                part,
+                                Rc::new(rest),
+                                Weight::Pixels(size),
+                            ),
+                            None => (*part).clone(),
+                        })
+                    })
+                    .unwrap_or_else(|| self.clone())
+            }
+            FlagGeometry::Rays(count, center, ray, field) => FlagGeometry::Rays(
+                *count,
+                *center,
+                *ray,
+                Rc::new(field.with_minimum_sizes(width, height)),
+            ),
+            FlagGeometry::Disc(x, y, radius, color, field) => FlagGeometry::Disc(
+                *x,
+                *y,
+                *radius,
+                *color,
+                Rc::new(field.with_minimum_sizes(width, height)),
+            ),
+            FlagGeometry::Emblem(image, size, shadow, field) => FlagGeometry::Emblem(
+                image.clone(),
+                *size,
+                *shadow,
+                Rc::new(field.with_minimum_sizes(width, height)),
+            ),
+            FlagGeometry::Mask(shape, content) => FlagGeometry::Mask(
+                Rc::new(shape.with_minimum_sizes(width, height)),
+                Rc::new(content.with_minimum_sizes(width, height)),
+            ),
+            FlagGeometry::Grid(across, down, mode) => FlagGeometry::Grid(
+                Rc::new(across.with_minimum_sizes(width, height)),
+                Rc::new(down.with_minimum_sizes(width, height)),
+                *mode,
+            ),
+            FlagGeometry::Alpha(opacity, geo) => {
+                FlagGeometry::Alpha(*opacity, Rc::new(geo.with_minimum_sizes(width, height)))
+            }
+            FlagGeometry::Flipped(direction, geo) => {
+                FlagGeometry::Flipped(*direction, Rc::new(geo.with_minimum_sizes(width, height)))
+            }
+            FlagGeometry::Solid(_) | FlagGeometry::TileFill(..) | FlagGeometry::Procedural(_) => {
+                self.clone()
+            }
+        }
+    }
+
+    /// Every rectangle painted when drawing the flag at the given size, along
+    /// with its color, in the order they are painted.
+    pub fn leaves(&self, width: u32, height: u32) -> Vec<(Rect, Color)> {
+        let mut canvas = RecordingCanvas::new(width, height);
+        self.draw(&mut canvas);
+        canvas.rectangles
+    }
+
+    /// The bounding box of everything painted in `color` at the given size.
+    pub fn color_bounds(&self, color: Color, width: u32, height: u32) -> Option<Rect> {
+        self.leaves(width, height)
+            .into_iter()
+            .filter(|(_, c)| *c == color)
+            .map(|(rect, _)| rect)
+            .fold(None, |bounds: Option<Rect>, rect| {
+                Some(bounds.map_or(rect, |bounds| bounds.union(&rect)))
+            })
+    }
+
+    /// Draws the flag laid out over `area`, but only touches the pixels within
+    /// `clip`. Parts of the tree that fall entirely outside of `clip` aren't
+    /// visited at all, which makes redrawing a small region cheap.
+    pub fn draw_area_clipped<P: MsPaint>(&self, buffer: &mut P, area: Rect, clip: Rect) {
+        let visible = match area.intersection(&clip) {
+            Some(visible) => visible,
+            None => return,
+        };
+
+        match self {
+            FlagGeometry::Horizontal(..) | FlagGeometry::Vertical(..) => {
+                for (part, rect) in split_run(self, FlagGeometry::as_split, area) {
+                    part.draw_area_clipped(buffer, rect, clip);
+                }
+            }
+            FlagGeometry::Mask(..)
+            | FlagGeometry::Grid(..)
+            | FlagGeometry::Alpha(..)
+            | FlagGeometry::Flipped(..) => {
+                let visible = Rect::new(
+                    visible.left - area.left,
+                    visible.top - area.top,
+                    visible.width,
+                    visible.height,
+                );
+                self.draw_layers(buffer, area, visible);
+            }
+            _ => self.draw_area(
+                &mut ClippedCanvas::new(buffer, clip),
+                area.left,
+                area.top,
+                area.width,
+                area.height,
+            ),
+        }
+    }
+
+    /// Draws the geometries that are put together from whole layers, such as
+    /// masks, over `area`. Only the part of each layer within `visible`,
+    /// relative to the area, is worked out and painted, so that drawing a
+    /// single row only holds a row of each layer. Anything else draws
+    /// nothing here.
+    fn draw_layers<P: MsPaint>(&self, buffer: &mut P, area: Rect, visible: Rect) {
+        let layer = |geo: &FlagGeometry, window: Rect| {
+            let mut layer = LayerCanvas::window(area.width, area.height, window);
+            geo.draw_area_clipped(&mut layer, Rect::new(0, 0, area.width, area.height), window);
+            layer
+        };
+        let (dx, dy) = (visible.left, visible.top);
+        let target = Rect::new(area.left + dx, area.top + dy, visible.width, visible.height);
+
+        match self {
+            FlagGeometry::Mask(shape, content) => {
+                let (coverage, layer) = (layer(shape, visible), layer(content, visible));
+                paint_runs(buffer, target, |x, y| {
+                    coverage.get(x + dx, y + dy).and(layer.get(x + dx, y + dy))
+                });
+            }
+            FlagGeometry::Grid(across, down, mode) => {
+                let (first, second) = (layer(across, visible), layer(down, visible));
+                paint_runs(buffer, target, |x, y| {
+                    match (first.get(x + dx, y + dy), second.get(x + dx, y + dy)) {
+                        (Some(a), Some(b)) => Some(mode.apply(&a, &b)),
+                        (a, b) => a.or(b),
+                    }
+                });
+            }
+            FlagGeometry::Alpha(opacity, geo) => {
+                // The whole geometry is faded at once, so that its parts don't
+                // show through one another
+                let layer = layer(geo, visible);
+                paint_runs(buffer, target, |x, y| {
+                    let Rgba([r, g, b, a]) = layer.get(x + dx, y + dy)?.to_rgba();
+                    Some(Color::Rgba([r, g, b, (a as f32 * opacity).round() as u8]))
+                });
+            }
+            FlagGeometry::Flipped(direction, geo) => {
+                let (width, height) = (area.width, area.height);
+                let mirrored = match direction {
+                    Direction::Horizontal => Rect {
+                        left: width - visible.left - visible.width,
+                        ..visible
+                    },
+                    Direction::Vertical => Rect {
+                        top: height - visible.top - visible.height,
+                        ..visible
+                    },
+                };
+                let layer = layer(geo, mirrored);
+                paint_runs(buffer, target, |x, y| match direction {
+                    Direction::Horizontal => layer.get(width - 1 - (x + dx), y + dy),
+                    Direction::Vertical => layer.get(x + dx, height - 1 - (y + dy)),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Draws the flag into the given sub-rectangle of the buffer.
+    pub fn draw_into<P: MsPaint>(
+        &self,
+        buffer: &mut P,
+        left: u32,
+        top: u32,
+        width: u32,
+        height: u32,
+    ) {
+        self.draw_area(buffer, left, top, width, height);
+    }
+
+    pub fn draw<P: MsPaint>(&self, buffer: &mut P) {
+        self.draw_area(buffer, 0, 0, buffer.width(), buffer.height());
+    }
+
+    /// Draws the flag onto a canvas chosen at runtime.
+    pub fn draw_dyn(&self, mut buffer: &mut dyn MsPaint) {
+        self.draw(&mut buffer);
+    }
+}
+
+/// Renders a flag into a fresh image. Pixels the flag leaves uncovered are
+/// black.
+pub fn render(geo: &FlagGeometry, width: u32, height: u32) -> RgbImage {
+    render_with_background(geo, width, height, Color::Black)
+}
+
+/// Renders a flag into a fresh image that is first filled with `background`,
+/// which shows through wherever the flag leaves pixels uncovered.
+pub fn render_with_background(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+    background: Color,
+) -> RgbImage {
+    let mut img = RgbImage::new(width, height);
+    img.clear(&background);
+    geo.draw(&mut img);
+    img
+}
+
+/// Renders a flag at a size so small, such as for a favicon, that some of its
+/// stripes would otherwise round away to nothing. Every part of every split
+/// is given at least one pixel, taken from the larger parts around it, and
+/// flags with more parts than there are pixels to go around are refused.
+pub fn render_small(geo: &FlagGeometry, width: u32, height: u32) -> Result<RgbImage, FlagError> {
+    let (min_width, min_height) = geo.minimum_size();
+    if width < min_width || height < min_height {
+        return Err(FlagError::TooSmall(min_width, min_height));
+    }
+
+    Ok(render(
+        &geo.with_minimum_sizes(width, height),
+        width,
+        height,
+    ))
+}
+
+/// Renders a flag with transparency, starting from a transparent image so
+/// that whatever the flag leaves uncovered or draws translucently can be
+/// seen through. The color channels are written as `alpha` says.
+pub fn render_rgba(geo: &FlagGeometry, width: u32, height: u32, alpha: AlphaMode) -> RgbaImage {
+    let mut img = RgbaImage::new(width, height);
+    geo.draw(&mut img);
+    if alpha == AlphaMode::Premultiplied {
+        for Rgba([r, g, b, a]) in img.pixels_mut() {
+            for channel in [r, g, b] {
+                *channel = ((*channel as u32 * *a as u32 + 127) / 255) as u8;
+            }
+        }
+    }
+    img
+}
+
+/// Renders a flag into part of an existing image, such as one cell of a
+/// sprite sheet, without allocating. The flag is laid out over the full
+/// `width` by `height` at the given offset, and whatever falls outside of the
+/// image is left out.
+pub fn render_into(
+    geo: &FlagGeometry,
+    img: &mut RgbImage,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+) {
+    let bounds = Rect::new(0, 0, img.width(), img.height());
+    geo.draw_area_clipped(img, Rect::new(left, top, width, height), bounds);
+}
+
+/// Renders one square tile of a flag laid out at `full_width` by
+/// `full_height`, such as for a map that loads the flag piece by piece as it
+/// is zoomed. Tile (`tile_x`, `tile_y`) covers the pixels from
+/// `tile_x * tile_size` and `tile_y * tile_size` onwards, so neighbouring
+/// tiles line up exactly. Tiles along the right and bottom edges are cut
+/// short where the flag ends.
+pub fn render_tile(
+    geo: &FlagGeometry,
+    full_width: u32,
+    full_height: u32,
+    tile_x: u32,
+    tile_y: u32,
+    tile_size: u32,
+) -> RgbImage {
+    let left = tile_x.saturating_mul(tile_size);
+    let top = tile_y.saturating_mul(tile_size);
+    let width = tile_size.min(full_width.saturating_sub(left));
+    let height = tile_size.min(full_height.saturating_sub(top));
+
+    let mut img = RgbImage::new(width, height);
+    let mut canvas = OffsetCanvas {
+        inner: &mut img,
+        left,
+        top,
+    };
+    geo.draw_area_clipped(
+        &mut canvas,
+        Rect::new(0, 0, full_width, full_height),
+        Rect::new(left, top, width, height),
+    );
+    img
+}
+
+/// Renders a flag along with a map of the painted regions, listing every
+/// rectangle and its color in the order they were drawn. Where regions
+/// overlap, the later one is the one that is visible.
+pub fn render_with_regions(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+) -> (RgbImage, Vec<(Rect, Color)>) {
+    (render(geo, width, height), geo.leaves(width, height))
+}
+
+/// Renders a flag with a key beside it on the right, in the form of a column
+/// of square swatches on white, one for each color of the flag in the order
+/// given by `colors`. The key is a quarter of the height wide, and swatches
+/// that don't fit are left out.
+pub fn render_with_legend(geo: &FlagGeometry, width: u32, height: u32) -> RgbImage {
+    let legend = (height / 4).max(1);
+    let flag = render(geo, width, height);
+    let mut img = RgbImage::from_fn(width + legend, height, |x, y| {
+        if x < width {
+            flag[(x, y)]
+        } else {
+            Color::White.to_rgb()
+        }
+    });
+
+    let margin = legend / 8;
+    let side = legend - 2 * margin;
+    let bounds = Rect::new(width, 0, legend, height);
+    let colors = geo
+        .colors()
+        .into_iter()
+        .filter(|color| *color != Color::None);
+    for (i, color) in colors.enumerate() {
+        let top = margin as i64 + i as i64 * (side + margin) as i64;
+        let swatch = clamp_rect((width + margin) as i64, top, side, side, bounds);
+        img.rectangle(swatch.left, swatch.top, swatch.width, swatch.height, &color);
+    }
+
+    img
+}
+
+/// Renders a flag with a stroke `stroke` pixels wide in `color` centered on
+/// every edge where two rectangles of different colors meet side by side.
+/// Shapes painted over a field, such as discs, only meet it inside its
+/// rectangle and so aren't outlined.
+pub fn render_outlined(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+    stroke: u32,
+    color: Color,
+) -> RgbImage {
+    let mut img = render(geo, width, height);
+    let leaves = geo
+        .leaves(width, height)
+        .into_iter()
+        .filter(|(_, color)| *color != Color::None)
+        .collect::<Vec<_>>();
+
+    let bounds = Rect::new(0, 0, width, height);
+    let half = (stroke / 2) as i64;
+    for (i, (a, a_color)) in leaves.iter().enumerate() {
+        for (b, b_color) in &leaves[i + 1..] {
+            if a_color == b_color {
+                continue;
+            }
+
+            let (top, bottom) = (a.top.max(b.top), a.bottom().min(b.bottom()));
+            let (left, right) = (a.left.max(b.left), a.right().min(b.right()));
+            // Where the rectangles meet, the later of their edges is the
+            // shared one
+            let mut edges = Vec::new();
+            if top < bottom && (a.right() == b.left || b.right() == a.left) {
+                edges.push(clamp_rect(
+                    left as i64 - half,
+                    top as i64,
+                    stroke,
+                    bottom - top,
+                    bounds,
+                ));
+            }
+            if left < right && (a.bottom() == b.top || b.bottom() == a.top) {
+                edges.push(clamp_rect(
+                    left as i64,
+                    top as i64 - half,
+                    right - left,
+                    stroke,
+                    bounds,
+                ));
+            }
+            for edge in edges {
+                img.rectangle(edge.left, edge.top, edge.width, edge.height, &color);
+            }
+        }
+    }
+
+    img
+}
+
+/// A flag along with its most recent rendering, which is reused for as long
+/// as the flag is rendered at the same size, such as on every frame of an
+/// editor.
+#[derive(Debug)]
+pub struct CachedFlag {
+    geo: FlagGeometry,
+    image: Option<RgbImage>,
+}
+
+impl CachedFlag {
+    pub fn new(geo: FlagGeometry) -> CachedFlag {
+        CachedFlag { geo, image: None }
+    }
+
+    pub fn geometry(&self) -> &FlagGeometry {
+        &self.geo
+    }
+
+    /// Replaces the flag, dropping the cached rendering of the old one.
+    pub fn set_geometry(&mut self, geo: FlagGeometry) {
+        self.geo = geo;
+        self.image = None;
+    }
+
+    /// Renders the flag like `render`, unless it was last rendered at this
+    /// size, in which case that image is returned as is.
+    pub fn render(&mut self, width: u32, height: u32) -> &RgbImage {
+        let geo = &self.geo;
+        match &mut self.image {
+            Some(img) if img.dimensions() == (width, height) => {}
+            image => *image = Some(render(geo, width, height)),
+        }
+        self.image.as_ref().unwrap()
+    }
+}
+
+/// Estimates the work of rendering a flag as the number of pixel writes,
+/// counting pixels that are painted over more than once each time.
+pub fn render_cost(geo: &FlagGeometry, width: u32, height: u32) -> u64 {
+    geo.leaves(width, height)
+        .iter()
+        .map(|(rect, _)| rect.width as u64 * rect.height as u64)
+        .sum()
+}
+
+/// Renders a flag and encodes it as PNG in memory.
+pub fn render_png(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+) -> Result<Vec<u8>, image::ImageError> {
+    encode_png(&render(geo, width, height))
+}
+
+fn encode_png(img: &RgbImage) -> Result<Vec<u8>, image::ImageError> {
+    let mut png = Vec::new();
+    image::png::PngEncoder::new(&mut png).encode(
+        img.as_raw(),
+        img.width(),
+        img.height(),
+        image::ColorType::Rgb8,
+    )?;
+    Ok(png)
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// The keyword of the PNG text chunk holding the FDL a flag was rendered from.
+const FDL_KEYWORD: &str = "FDL";
+
+/// The CRC-32 that guards every PNG chunk.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Splits a PNG into its chunks, as pairs of chunk type and data, or returns
+/// `None` if it isn't well formed, such as when it is cut short. The `png`
+/// decoder that comes with `image` 0.23 doesn't hand out text chunks, so they
+/// are read here instead.
+fn png_chunks(png: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    let mut rest = png.strip_prefix(PNG_SIGNATURE)?;
+    let mut chunks = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 12 {
+            return None;
+        }
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let size = length.checked_add(12)?;
+        if rest.len() < size {
+            return None;
+        }
+        chunks.push((&rest[4..8], &rest[8..8 + length]));
+        rest = &rest[size..];
+    }
+    Some(chunks)
+}
+
+/// Adds a `tEXt` chunk right after the header of a PNG. Text chunks are
+/// Latin-1, so text with other characters can't be stored and gives `None`.
+fn png_with_text(png: &[u8], keyword: &str, text: &str) -> Option<Vec<u8>> {
+    let mut data = keyword.as_bytes().to_vec();
+    data.push(0);
+    for c in text.chars() {
+        if c as u32 > 0xff {
+            return None;
+        }
+        data.push(c as u8);
+    }
+
+    png_with_chunk(png, b"tEXt", &data)
+}
+
+/// Adds a chunk right after the header of a PNG, where metadata that must
+/// precede the image data belongs.
+fn png_with_chunk(png: &[u8], kind: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = PNG_SIGNATURE.to_vec();
+    for (i, (existing, chunk)) in png_chunks(png)?.into_iter().enumerate() {
+        write_png_chunk(&mut out, existing, chunk);
+        if i == 0 {
+            write_png_chunk(&mut out, kind, data);
+        }
+    }
+    Some(out)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Finds the `tEXt` chunk with the given keyword in a PNG.
+fn png_text(png: &[u8], keyword: &str) -> Option<String> {
+    png_chunks(png)?
+        .into_iter()
+        .filter(|(kind, _)| *kind == b"tEXt")
+        .find_map(|(_, data)| {
+            let nul = data.iter().position(|&b| b == 0)?;
+            if &data[..nul] != keyword.as_bytes() {
+                return None;
+            }
+            Some(data[nul + 1..].iter().map(|&b| b as char).collect())
+        })
+}
+
+/// A canvas as tall as the whole flag that only keeps a single row, prefixed
+/// by the PNG filter byte.
+struct RowCanvas {
+    width: u32,
+    height: u32,
+    y: u32,
+    row: Vec<u8>,
+}
+
+impl MsPaint for RowCanvas {
+    fn rectangle(&mut self, left: u32, top: u32, width: u32, height: u32, color: &Color) {
+        let rgba = color.to_rgba();
+        if rgba.0[3] == 0 || self.y < top || self.y >= top + height {
+            return;
+        }
+
+        // Blends translucent colors the same way as an `RgbImage`
+        for x in left..(left + width) {
+            let i = 1 + 3 * x as usize;
+            let Rgba([r, g, b, _]) = blend(
+                rgba,
+                [self.row[i], self.row[i + 1], self.row[i + 2], 255].into(),
+            );
+            self.row[i..i + 3].copy_from_slice(&[r, g, b]);
+        }
+    }
+
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+/// Renders a flag as PNG one row at a time, writing each row out as soon as
+/// it is done, so only a single row is ever held in memory, along with a
+/// row of each layer of masks, grids and faded parts. The pixels are
+/// the same as those of `render_png`, but the image data is stored without
+/// compression.
+pub fn render_png_streaming<W: Write>(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+    mut out: W,
+) -> std::io::Result<()> {
+    let mut chunk = Vec::new();
+    let mut write_chunk = |out: &mut W, kind: &[u8], data: &[u8]| {
+        chunk.clear();
+        write_png_chunk(&mut chunk, kind, data);
+        out.write_all(&chunk)
+    };
+
+    out.write_all(PNG_SIGNATURE)?;
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header)?;
+
+    // A zlib stream of stored deflate blocks, each holding at most 65535
+    // bytes, with every row prefixed by the filter type none
+    let mut adler = (1u32, 0u32);
+    let mut data = vec![0x78, 0x01];
+    let mut canvas = RowCanvas {
+        width,
+        height,
+        y: 0,
+        row: Vec::new(),
+    };
+    for y in 0..height {
+        canvas.y = y;
+        canvas.row.clear();
+        canvas.row.resize(1 + 3 * width as usize, 0);
+        geo.draw_area_clipped(
+            &mut canvas,
+            Rect::new(0, 0, width, height),
+            Rect::new(0, y, width, 1),
+        );
+
+        let blocks = canvas.row.chunks(0xffff).count();
+        for (i, block) in canvas.row.chunks(0xffff).enumerate() {
+            let last = y + 1 == height && i + 1 == blocks;
+            let len = block.len() as u16;
+            data.push(last as u8);
+            data.extend_from_slice(&len.to_le_bytes());
+            data.extend_from_slice(&(!len).to_le_bytes());
+            data.extend_from_slice(block);
+        }
+        for &byte in &canvas.row {
+            adler.0 = (adler.0 + byte as u32) % 65521;
+            adler.1 = (adler.1 + adler.0) % 65521;
+        }
+
+        write_chunk(&mut out, b"IDAT", &data)?;
+        data.clear();
+    }
+
+    if height == 0 {
+        data.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    data.extend_from_slice(&(adler.1 << 16 | adler.0).to_be_bytes());
+    write_chunk(&mut out, b"IDAT", &data)?;
+    write_chunk(&mut out, b"IEND", &[])
+}
+
+/// Reads back the FDL that `flagrant render` embeds in the PNGs it writes.
+pub fn fdl_from_png(path: &str) -> Option<String> {
+    png_text(&std::fs::read(path).ok()?, FDL_KEYWORD)
+}
+
+/// Renders a flag as PNG, marked with a resolution in dots per inch so that
+/// it prints at the intended physical size. Only the metadata differs from
+/// `render_png`.
+pub fn render_png_with_dpi(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+    dpi: f32,
+) -> Result<Vec<u8>, image::ImageError> {
+    let png = render_png(geo, width, height)?;
+
+    // PNG counts pixels per meter rather than per inch
+    let per_meter = ((dpi / 0.0254).round() as u32).to_be_bytes();
+    let mut phys = Vec::with_capacity(9);
+    phys.extend_from_slice(&per_meter);
+    phys.extend_from_slice(&per_meter);
+    phys.push(1);
+
+    Ok(png_with_chunk(&png, b"pHYs", &phys).unwrap_or(png))
+}
+
+/// Renders a flag as a `data:` URI holding a base64 encoded PNG, ready to be
+/// embedded in HTML or JSON.
+pub fn render_data_uri(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+) -> Result<String, image::ImageError> {
+    let png = render_png(geo, width, height)?;
+    Ok(format!("data:image/png;base64,{}", base64(&png)))
+}
+
+/// Encodes bytes using the standard base64 alphabet, with padding.
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Renders a flag into a fresh image, wrapped for format-agnostic handling.
+pub fn render_dynamic(geo: &FlagGeometry, width: u32, height: u32) -> DynamicImage {
+    DynamicImage::ImageRgb8(render(geo, width, height))
+}
+
+/// Renders a flag with smoothed edges by drawing it at `samples` times the
+/// resolution in each direction and averaging each block of subpixels down
+/// to a single pixel.
+pub fn render_antialiased(geo: &FlagGeometry, width: u32, height: u32, samples: u32) -> RgbImage {
+    render_antialiased_with(geo, width, height, samples, Blending::Srgb)
+}
+
+/// Renders a flag with smoothed edges like `render_antialiased`, averaging
+/// the subpixels as given by `blending`.
+pub fn render_antialiased_with(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+    samples: u32,
+    blending: Blending,
+) -> RgbImage {
+    let samples = samples.max(1);
+    let large = render(geo, width * samples, height * samples);
+
+    let mut img = RgbImage::new(width, height);
+    let mut block = Vec::with_capacity((samples * samples) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            block.clear();
+            for sy in 0..samples {
+                for sx in 0..samples {
+                    block.push(large[(x * samples + sx, y * samples + sy)]);
+                }
+            }
+
+            img[(x, y)] = blending.average(&block);
+        }
+    }
+
+    img
+}
+
+/// Makes a rendered flag look like it is waving, by shifting each column up
+/// or down along a sine wave. `wavelength` is the number of columns in one
+/// period of the wave, and `phase` is in radians. Pixels uncovered at the
+/// top and bottom are left black.
+pub fn wave(img: &RgbImage, amplitude: u32, wavelength: u32, phase: f32) -> RgbImage {
+    let period = wavelength.max(1) as f32;
+    let offsets = (0..img.width())
+        .map(|x| {
+            let angle = 2.0 * std::f32::consts::PI * x as f32 / period + phase;
+            (amplitude as f32 * angle.sin()).round() as i64
+        })
+        .collect::<Vec<_>>();
+
+    RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let source = y as i64 - offsets[x as usize];
+        if (0..img.height() as i64).contains(&source) {
+            img[(x, source as u32)]
+        } else {
+            Color::Black.to_rgb()
+        }
+    })
+}
+
+/// Proposes a flag approximating an image, made up of `bands` stripes in
+/// the given direction, each colored with the average of the pixels it
+/// covers.
+pub fn approximate_flag(img: &RgbImage, bands: u32, direction: Direction) -> FlagGeometry {
+    let bands = bands.max(1);
+    let length = direction.length(img.width(), img.height());
+
+    let colors = (0..bands)
+        .map(|band| {
+            let start = band * length / bands;
+            let end = ((band + 1) * length / bands).max(start + 1).min(length);
+
+            let mut sum = [0u64; 3];
+            let mut count = 0;
+            for (x, y, Rgb(rgb)) in img.enumerate_pixels() {
+                let position = match direction {
+                    Direction::Horizontal => x,
+                    Direction::Vertical => y,
+                };
+                if position >= start && position < end {
+                    for (total, channel) in sum.iter_mut().zip(rgb.iter()) {
+                        *total += *channel as u64;
+                    }
+                    count += 1;
+                }
+            }
+
+            let count = count.max(1);
+            Color::Rgb(sum.map(|total| ((total + count / 2) / count) as u8))
+        })
+        .collect::<Vec<_>>();
+
+    // Peel off one band at a time, giving it its share of what remains
+    let mut geo = FlagGeometry::Solid(colors[colors.len() - 1]);
+    for (i, color) in colors.iter().enumerate().rev().skip(1) {
+        let pivot = 100.0 / (bands - i as u32) as f32;
+        let band = Rc::new(FlagGeometry::Solid(*color));
+        geo = FlagGeometry::split(direction, band, Rc::new(geo), Weight::Percent(pivot));
+    }
+
+    geo
+}
+
+/// A tiny SplitMix64 generator, so random flags are reproducible without
+/// pulling in a dependency.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Builds a random flag out of splits and solids, at most `max_depth` levels
+/// deep. The same seed always gives the same flag.
+pub fn random_flag(seed: u64, max_depth: u32) -> FlagGeometry {
+    fn generate(rng: &mut SplitMix64, depth: u32) -> FlagGeometry {
+        const COLORS: [Color; 6] = [
+            Color::Blue,
+            Color::Green,
+            Color::Red,
+            Color::White,
+            Color::Yellow,
+            Color::Black,
+        ];
+
+        if depth <= 1 || rng.below(3) == 0 {
+            return FlagGeometry::Solid(COLORS[rng.below(COLORS.len() as u64) as usize]);
+        }
+
+        let pivot = Weight::Percent((10 + rng.below(81)) as f32);
+        let car = Rc::new(generate(rng, depth - 1));
+        let cdr = Rc::new(generate(rng, depth - 1));
+        let direction = if rng.below(2) == 0 {
+            Direction::Horizontal
+        } else {
+            Direction::Vertical
+        };
+        FlagGeometry::split(direction, car, cdr, pivot)
+    }
+
+    generate(&mut SplitMix64(seed), max_depth)
+}
+
+/// Counts the pixels that differ between two flags rendered at the same size.
+pub fn pixel_difference(a: &FlagGeometry, b: &FlagGeometry, width: u32, height: u32) -> usize {
+    let a = render(a, width, height);
+    let b = render(b, width, height);
+    a.pixels().zip(b.pixels()).filter(|(a, b)| a != b).count()
+}
+
+/// Checks whether two flags render identically at the given size.
+pub fn flags_equal(a: &FlagGeometry, b: &FlagGeometry, width: u32, height: u32) -> bool {
+    pixel_difference(a, b, width, height) == 0
+}
+
+/// Checks whether two flags render alike at the given size, allowing every
+/// channel of every pixel to be off by up to `max_channel_diff`.
+pub fn flags_approx_equal(
+    a: &FlagGeometry,
+    b: &FlagGeometry,
+    width: u32,
+    height: u32,
+    max_channel_diff: u8,
+) -> bool {
+    let a = render(a, width, height);
+    let b = render(b, width, height);
+    a.pixels().zip(b.pixels()).all(|(Rgb(a), Rgb(b))| {
+        a.iter()
+            .zip(b.iter())
+            .all(|(a, b)| a.max(b) - a.min(b) <= max_channel_diff)
+    })
+}
+
+/// Flags of some countries, by name, as written in FDL.
+pub const PRESETS: &[(&str, &str)] = &[
+    ("france", "(h 33 (s b) (h 50 (s w) (s r)))"),
+    ("madagascar", "(h 33 (s w) (v 50 (s r) (s g)))"),
+    ("japan", "(disc 50 50 30 r (s w))"),
+    (
+        "sweden",
+        "(v 40 (t top_strip (h 40 (h 65 (s b) (s y)) (s b))) (v 30 (s y) (r top_strip)))",
+    ),
+];
+
+/// How alike two flags look when rendered at the given size, from 0 for
+/// black against white everywhere to 1 for identical. This is one less the
+/// mean distance between the RGB values of each pair of pixels, relative to
+/// the largest possible distance.
+pub fn similarity(a: &FlagGeometry, b: &FlagGeometry, width: u32, height: u32) -> f32 {
+    let a = render(a, width, height);
+    let b = render(b, width, height);
+    let max = (3.0 * 255.0f32 * 255.0).sqrt();
+    let total = a
+        .pixels()
+        .zip(b.pixels())
+        .map(|(Rgb(a), Rgb(b))| {
+            let squares = (0..3).map(|i| (a[i] as f32 - b[i] as f32).powi(2));
+            squares.sum::<f32>().sqrt() / max
+        })
+        .sum::<f32>();
+    1.0 - total / (width * height).max(1) as f32
+}
+
+/// The preset that the flag looks most like when both are rendered at the
+/// given size, along with how alike they are as given by `similarity`, such
+/// as to warn that a new design resembles an existing flag.
+pub fn nearest_preset(geo: &FlagGeometry, width: u32, height: u32) -> Option<(String, f32)> {
+    PRESETS
+        .iter()
+        .filter_map(|(name, fdl)| {
+            let preset = parse_flag(fdl).ok()?;
+            Some((name.to_string(), similarity(geo, &preset, width, height)))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+}
+
+/// How `render_fit` reconciles a flag's intended aspect ratio with a target
+/// size of a different shape.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FitMode {
+    /// Scale the flag to fit entirely, letterboxing the rest.
+    Contain,
+    /// Scale the flag to fill the target, cropping what spills over.
+    Cover,
+}
+
+/// Renders a flag with an intended `ratio` (width divided by height) into a
+/// `width` by `height` image without stretching it.
+pub fn render_fit(
+    geo: &FlagGeometry,
+    width: u32,
+    height: u32,
+    ratio: f32,
+    mode: FitMode,
+    background: Color,
+) -> RgbImage {
+    let wider = width as f32 / height as f32 > ratio;
+    let (fit_width, fit_height) = match (mode, wider) {
+        (FitMode::Contain, true) | (FitMode::Cover, false) => {
+            ((height as f32 * ratio).round() as u32, height)
+        }
+        (FitMode::Contain, false) | (FitMode::Cover, true) => {
+            (width, (width as f32 / ratio).round() as u32)
+        }
+    };
+
+    let mut img = RgbImage::new(width, height);
+    match mode {
+        FitMode::Contain => {
+            img.clear(&background);
+            geo.draw_area(
+                &mut img,
+                (width - fit_width) / 2,
+                (height - fit_height) / 2,
+                fit_width,
+                fit_height,
+            );
+        }
+        FitMode::Cover => {
+            let mut full = RgbImage::new(fit_width, fit_height);
+            geo.draw(&mut full);
+
+            let left = (fit_width - width) / 2;
+            let top = (fit_height - height) / 2;
+            for y in 0..height {
+                for x in 0..width {
+                    img[(x, y)] = full[(left + x, top + y)];
+                }
+            }
+        }
+    }
+
+    img
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SExpr {
+    List(Vec<SExpr>),
+    Literal(String),
+}
+
+/// The byte range in the source text that an expression was parsed from,
+/// along with those of the items of a list, in the same shape as the `SExpr`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Spans {
+    pub span: std::ops::Range<usize>,
+    pub children: Vec<Spans>,
+}
+
+/// Where each node of a geometry converted by `to_flag_geometry_spanned` was
+/// written in the source text. The nodes are kept alive by the map, so a node
+/// is always found by the address it has in the geometry.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    nodes: Vec<(Rc<UnresolvedFlagGeometry>, std::ops::Range<usize>)>,
+}
+
+impl SourceMap {
+    /// The source text range of a node of the geometry.
+    pub fn span(&self, geo: &UnresolvedFlagGeometry) -> Option<std::ops::Range<usize>> {
+        self.nodes
+            .iter()
+            .find(|(node, _)| std::ptr::eq(Rc::as_ptr(node), geo))
+            .map(|(_, span)| span.clone())
+    }
+
+    fn record(&mut self, geo: &Rc<UnresolvedFlagGeometry>, spans: &Spans) {
+        self.nodes.push((geo.clone(), spans.span.clone()));
+
+        // The position of each part within the list it was written in
+        let mut parts = |parts: &[(&Rc<UnresolvedFlagGeometry>, usize)]| {
+            for (part, i) in parts {
+                if let Some(spans) = spans.children.get(*i) {
+                    self.record(part, spans);
+                }
+            }
+        };
+        match &**geo {
+            UnresolvedFlagGeometry::Horizontal(car, cdr, _)
+            | UnresolvedFlagGeometry::Vertical(car, cdr, _) => {
+                // With a weight in front of each part, the second one is
+                // written after its weight
+                let cdr_at = spans.children.len().saturating_sub(1);
+                parts(&[(car, 2), (cdr, cdr_at)]);
+            }
+            UnresolvedFlagGeometry::Rays(.., field) => parts(&[(field, 4)]),
+            UnresolvedFlagGeometry::Disc(.., field) => parts(&[(field, 5)]),
+            UnresolvedFlagGeometry::Tint(_, geo)
+            | UnresolvedFlagGeometry::Alpha(_, geo)
+            | UnresolvedFlagGeometry::Tag(_, geo) => parts(&[(geo, 2)]),
+            // Only ever made by transforming a flag, so never written out
+            UnresolvedFlagGeometry::Flipped(..) => {}
+            UnresolvedFlagGeometry::Emblem(.., field)
+            | UnresolvedFlagGeometry::Fimbriation(.., field) => parts(&[(field, 3)]),
+            UnresolvedFlagGeometry::Mask(shape, content)
+            | UnresolvedFlagGeometry::Grid(shape, content, _) => parts(&[(shape, 1), (content, 2)]),
+            UnresolvedFlagGeometry::Solid(_)
+            | UnresolvedFlagGeometry::TileFill(..)
+            | UnresolvedFlagGeometry::Reference(_)
+            | UnresolvedFlagGeometry::Include(_) => {}
+        }
+    }
+}
+
+/// Whether a character separates expressions. Besides whitespace, this
+/// includes the byte order mark that some editors put at the start of a file.
+fn is_blank(c: char) -> bool {
+    c.is_whitespace() || c == '\u{feff}'
+}
+
+impl SExpr {
+    pub fn parse<I>(input: &mut std::iter::Peekable<I>) -> Option<SExpr>
+    where
+        I: Iterator<Item = char>,
+    {
+        let mut sexpr = None;
+        let mut quoted = false;
+        while let Some(c) = input.peek() {
+            match sexpr {
+                None if is_blank(*c) => {}
+                None if *c == ')' => return None,
+                None if *c == '(' => {
+                    sexpr = Some(SExpr::List(Vec::new()));
+                }
+                None if *c == '"' => {
+                    quoted = true;
+                    sexpr = Some(SExpr::Literal(String::new()));
+                }
+                None => {
+                    sexpr = Some(SExpr::Literal(c.to_string()));
+                }
+                Some(SExpr::List(_)) if *c == ')' => {
+                    input.next();
+                    break;
+                }
+                Some(SExpr::List(_)) if is_blank(*c) => {}
+                Some(SExpr::List(ref mut list)) => {
+                    list.push(SExpr::parse(input)?);
+                    continue;
+                }
+                Some(SExpr::Literal(_)) if quoted && *c == '"' => {
+                    quoted = false;
+                    input.next();
+                    break;
+                }
+                Some(SExpr::Literal(_)) if !quoted && (is_blank(*c) || *c == ')') => {
+                    break;
+                }
+                Some(SExpr::Literal(ref mut literal)) => literal.push(*c),
+            }
+
+            input.next();
+        }
+
+        if quoted {
+            return None;
+        }
+
+        while let Some(c) = input.peek() {
+            if is_blank(*c) {
+                input.next();
+            } else {
+                break;
+            }
+        }
+
+        sexpr
+    }
+
+    /// Parses one expression after another until the input runs out, such as
+    /// for a file holding several flags separated by whitespace. Parsing
+    /// stops at the first expression that isn't well formed.
+    pub fn parse_all<I>(input: I) -> impl Iterator<Item = SExpr>
+    where
+        I: Iterator<Item = char>,
+    {
+        let mut input = input.peekable();
+        std::iter::from_fn(move || SExpr::parse(&mut input))
+    }
+
+    /// Parses an expression the same way as `parse`, but keeps track of the
+    /// open lists on the heap rather than on the call stack, so there is no
+    /// limit to how deeply lists can be nested.
+    pub fn parse_iter<I>(input: &mut std::iter::Peekable<I>) -> Option<SExpr>
+    where
+        I: Iterator<Item = char>,
+    {
+        fn skip_whitespace<I: Iterator<Item = char>>(input: &mut std::iter::Peekable<I>) {
+            while input.next_if(|c| is_blank(*c)).is_some() {}
+        }
+
+        let mut open: Vec<Vec<SExpr>> = Vec::new();
+        loop {
+            skip_whitespace(input);
+            let sexpr = match input.peek() {
+                // Lists still open at the end of the input are closed there
+                None => SExpr::List(open.pop()?),
+                Some(')') => {
+                    let list = open.pop()?;
+                    input.next();
+                    SExpr::List(list)
+                }
+                Some('(') => {
+                    input.next();
+                    open.push(Vec::new());
+                    continue;
+                }
+                Some('"') => {
+                    input.next();
+                    let mut literal = String::new();
+                    loop {
+                        match input.next()? {
+                            '"' => break,
+                            c => literal.push(c),
+                        }
+                    }
+                    SExpr::Literal(literal)
+                }
+                Some(_) => {
+                    let mut literal = String::new();
+                    while let Some(c) = input.next_if(|c| !is_blank(*c) && *c != ')') {
+                        literal.push(c);
+                    }
+                    SExpr::Literal(literal)
+                }
+            };
+
+            match open.last_mut() {
+                Some(list) => list.push(sexpr),
+                None => {
+                    skip_whitespace(input);
+                    return Some(sexpr);
+                }
+            }
+        }
+    }
+
+    /// Parses an expression like `parse`, also returning where in `fdl` each
+    /// part of it was written, such as for pointing out errors in an editor.
+    pub fn parse_with_spans(fdl: &str) -> Option<(SExpr, Spans)> {
+        SExpr::parse_spanned(&mut fdl.char_indices().peekable(), fdl.len())
+    }
+
+    fn parse_spanned(
+        input: &mut std::iter::Peekable<std::str::CharIndices>,
+        len: usize,
+    ) -> Option<(SExpr, Spans)> {
+        fn skip_whitespace(input: &mut std::iter::Peekable<std::str::CharIndices>) {
+            while input.next_if(|(_, c)| is_blank(*c)).is_some() {}
+        }
+        let offset = |input: &mut std::iter::Peekable<std::str::CharIndices>| {
+            input.peek().map_or(len, |&(i, _)| i)
+        };
+
+        skip_whitespace(input);
+        let start = offset(input);
+        let (sexpr, children) = match input.peek()?.1 {
+            ')' => return None,
+            '(' => {
+                input.next();
+                let (mut list, mut children) = (Vec::new(), Vec::new());
+                loop {
+                    skip_whitespace(input);
+                    match input.peek() {
+                        // Lists still open at the end of the input are closed there
+                        None => break,
+                        Some((_, ')')) => {
+                            input.next();
+                            break;
+                        }
+                        Some(_) => {
+                            let (sexpr, spans) = SExpr::parse_spanned(input, len)?;
+                            list.push(sexpr);
+                            children.push(spans);
+                        }
+                    }
+                }
+                (SExpr::List(list), children)
+            }
+            '"' => {
+                input.next();
+                let mut literal = String::new();
+                loop {
+                    match input.next()?.1 {
+                        '"' => break,
+                        c => literal.push(c),
+                    }
+                }
+                (SExpr::Literal(literal), Vec::new())
+            }
+            _ => {
+                let mut literal = String::new();
+                while let Some((_, c)) = input.next_if(|(_, c)| !is_blank(*c) && *c != ')') {
+                    literal.push(c);
+                }
+                (SExpr::Literal(literal), Vec::new())
+            }
+        };
+
+        let span = start..offset(input);
+        Some((sexpr, Spans { span, children }))
+    }
+
+    /// Writes the expression over several lines, the way the examples in the
+    /// README are laid out. Lists nested no more than two deep, such as
+    /// `(h 50 (s r) (s w))`, stay on one line. Deeper ones keep the literals
+    /// they start with on their first line, put everything else on lines of
+    /// its own indented by `indent` more spaces, and close on a line of their
+    /// own.
+    pub fn to_pretty_string(&self, indent: usize) -> String {
+        let mut out = String::new();
+        self.write_pretty(indent, 0, &mut out);
+        out
+    }
+
+    fn write_pretty(&self, indent: usize, depth: usize, out: &mut String) {
+        let has_lists = |expr: &SExpr| {
+            expr.list()
+                .is_some_and(|list| list.iter().any(|expr| expr.list().is_some()))
+        };
+        let list = match self.list() {
+            Some(list) if list.iter().any(has_lists) => list,
+            _ => {
+                out.push_str(&self.to_string());
+                return;
+            }
+        };
+
+        let head = list
+            .iter()
+            .take_while(|expr| expr.literal().is_some())
+            .count();
+        out.push('(');
+        for (i, expr) in list[..head].iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            out.push_str(&expr.to_string());
+        }
+        for expr in &list[head..] {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent * (depth + 1)));
+            expr.write_pretty(indent, depth + 1, out);
+        }
+        out.push('\n');
+        out.push_str(&" ".repeat(indent * depth));
+        out.push(')');
+    }
+
+    pub fn list(&self) -> Option<&[SExpr]> {
+        match self {
+            SExpr::List(list) => Some(list.as_slice()),
+            SExpr::Literal(_) => None,
+        }
+    }
+
+    pub fn literal(&self) -> Option<&str> {
+        match self {
+            SExpr::Literal(literal) => Some(literal.as_str()),
+            SExpr::List(_) => None,
+        }
+    }
+
+    pub fn to_flag_geometry(&self) -> Result<UnresolvedFlagGeometry, FlagError> {
+        self.to_flag_geometry_in(&Dialect::default())
+    }
+
+    /// Converts the expression like `to_flag_geometry`, along with a map from
+    /// each node of the geometry to where it was written, given the spans
+    /// from `parse_with_spans`.
+    pub fn to_flag_geometry_spanned(
+        &self,
+        spans: &Spans,
+    ) -> Result<(Rc<UnresolvedFlagGeometry>, SourceMap), FlagError> {
+        let geo = Rc::new(self.to_flag_geometry()?);
+        let mut map = SourceMap::default();
+        map.record(&geo, spans);
+        Ok((geo, map))
+    }
+
+    /// Like `to_flag_geometry`, but colors that aren't built in are looked up
+    /// by name in `palette`, so the same FDL can be rendered in different
+    /// color schemes.
+    pub fn to_flag_geometry_with_palette(
+        &self,
+        palette: &HashMap<String, Color>,
+    ) -> Result<UnresolvedFlagGeometry, FlagError> {
+        let dialect = Dialect {
+            palette: palette.clone(),
+            ..Dialect::default()
+        };
+        self.to_flag_geometry_in(&dialect)
+    }
+
+    /// Like `to_flag_geometry`, but also understands the operator aliases and
+    /// color names of `dialect`.
+    pub fn to_flag_geometry_in(
+        &self,
+        dialect: &Dialect,
+    ) -> Result<UnresolvedFlagGeometry, FlagError> {
+        let invalid = || FlagError::InvalidExpression(self.to_string());
+        let list = self
+            .list()
+            .ok_or_else(|| FlagError::ExpectedList(self.to_string()))?;
+        let written = list.first().and_then(SExpr::literal).ok_or_else(invalid)?;
+        let op = dialect.operator(written);
+
+        let color = |expr: &SExpr| {
+            expr.literal()
+                .and_then(|lit| {
+                    lit.parse()
+                        .ok()
+                        .or_else(|| dialect.palette.get(lit).copied())
+                })
+                .ok_or_else(|| FlagError::BadColor(expr.to_string()))
+        };
+        let weight = |expr: &SExpr| expr.literal().and_then(parse_weight).ok_or_else(invalid);
+
+        if let Ok(direction) = op.parse() {
+            return match &list[1..] {
+                [pivot, car @ SExpr::List(_), cdr @ SExpr::List(_)] => {
+                    let pivot = pivot.literal().and_then(parse_pivot).ok_or_else(invalid)?;
+                    let car = Rc::new(car.to_flag_geometry_in(dialect)?);
+                    let cdr = Rc::new(cdr.to_flag_geometry_in(dialect)?);
+                    Ok(UnresolvedFlagGeometry::split(direction, car, cdr, pivot))
+                }
+                // Each part with a weight of its own, where `*` takes what the
+                // other one leaves, or half of the area if both do. Two
+                // percentages are shares of their sum, so `1 x 2 y` gives `x`
+                // a third, while next to a size in pixels a percentage takes
+                // the rest like `*`
+                [first, car @ SExpr::List(_), second, cdr @ SExpr::List(_)] => {
+                    let part = |expr: &SExpr| match expr.literal() {
+                        Some("*") => Ok(None),
+                        lit => lit.and_then(parse_pivot).map(Some).ok_or_else(invalid),
+                    };
+                    let pivot = match (part(first)?, part(second)?) {
+                        (Some(pivot), None) => pivot,
+                        (None, Some(pivot)) => pivot.mirrored(),
+                        (None, None) => Weight::Percent(50.0),
+                        (Some(pivot), Some(Weight::Percent(_))) if pivot.is_pixels() => pivot,
+                        (Some(Weight::Percent(_)), Some(pivot)) if pivot.is_pixels() => {
+                            pivot.mirrored()
+                        }
+                        (Some(Weight::Percent(a)), Some(Weight::Percent(b))) if a + b > 0.0 => {
+                            Weight::Percent(100.0 * a / (a + b))
+                        }
+                        (Some(_), Some(_)) => {
+                            return Err(FlagError::MalformedSplit(self.to_string()))
+                        }
+                    };
+                    let car = Rc::new(car.to_flag_geometry_in(dialect)?);
+                    let cdr = Rc::new(cdr.to_flag_geometry_in(dialect)?);
+                    Ok(UnresolvedFlagGeometry::split(direction, car, cdr, pivot))
+                }
+                _ => Err(FlagError::MalformedSplit(self.to_string())),
+            };
+        }
+
+        match (op, &list[1..]) {
+            ("s", [c]) => Ok(UnresolvedFlagGeometry::Solid(color(c)?)),
+            ("rays", [count, center, ray, field]) => {
+                let count = count
+                    .literal()
+                    .ok_or_else(invalid)?
+                    .parse()
+                    .map_err(|_| invalid())?;
+                let field = Rc::new(field.to_flag_geometry_in(dialect)?);
+                Ok(UnresolvedFlagGeometry::Rays(
+                    count,
+                    color(center)?,
+                    color(ray)?,
+                    field,
+                ))
+            }
+            ("disc", [x, y, radius, c, field]) => {
+                let field = Rc::new(field.to_flag_geometry_in(dialect)?);
+                Ok(UnresolvedFlagGeometry::Disc(
+                    weight(x)?,
+                    weight(y)?,
+                    weight(radius)?,
+                    color(c)?,
+                    field,
+                ))
+            }
+            ("tilefill", [direction, stripe, a, b]) => {
+                let direction = direction
+                    .literal()
+                    .and_then(|lit| lit.parse().ok())
+                    .ok_or_else(invalid)?;
+                Ok(UnresolvedFlagGeometry::TileFill(
+                    direction,
+                    weight(stripe)?,
+                    color(a)?,
+                    color(b)?,
+                ))
+            }
+            ("t", [tag, geo]) => {
+                let tag = tag.literal().ok_or_else(invalid)?.trim().to_string();
+                let geo = Rc::new(geo.to_flag_geometry_in(dialect)?);
+                Ok(UnresolvedFlagGeometry::Tag(tag, geo))
+            }
+            ("r", [tag]) => {
+                let tag = tag.literal().ok_or_else(invalid)?.trim().to_string();
+                Ok(UnresolvedFlagGeometry::Reference(tag))
+            }
+            ("tint", [tint, geo]) => {
+                let geo = Rc::new(geo.to_flag_geometry_in(dialect)?);
+                Ok(UnresolvedFlagGeometry::Tint(color(tint)?, geo))
+            }
+            ("emblem", [path, size, field, shadow @ ..]) => {
+                let path = path.literal().ok_or_else(invalid)?.to_string();
+                let field = Rc::new(field.to_flag_geometry_in(dialect)?);
+                let offset = |expr: &SExpr| {
+                    expr.literal()
+                        .and_then(|lit| lit.parse().ok())
+                        .ok_or_else(invalid)
+                };
+                let shadow = match shadow {
+                    [] => None,
+                    [dx, dy, c] => Some(Shadow {
+                        dx: offset(dx)?,
+                        dy: offset(dy)?,
+                        color: color(c)?,
+                    }),
+                    _ => return Err(invalid()),
+                };
+                Ok(UnresolvedFlagGeometry::Emblem(
+                    path,
+                    weight(size)?,
+                    shadow,
+                    field,
+                ))
+            }
+            ("fimb", [thickness, c, geo]) => {
+                let thickness = thickness
+                    .literal()
+                    .and_then(|lit| lit.parse().ok())
+                    .ok_or_else(invalid)?;
+                let geo = Rc::new(geo.to_flag_geometry_in(dialect)?);
+                Ok(UnresolvedFlagGeometry::Fimbriation(
+                    thickness,
+                    color(c)?,
+                    geo,
+                ))
+            }
+            ("alpha", [opacity, geo]) => {
+                let opacity = weight(opacity)?;
+                if opacity > 1.0 {
+                    return Err(invalid());
+                }
+                let geo = Rc::new(geo.to_flag_geometry_in(dialect)?);
+                Ok(UnresolvedFlagGeometry::Alpha(opacity, geo))
+            }
+            ("grid", [across, down, mode]) => {
+                let mode = mode
+                    .literal()
+                    .and_then(|lit| lit.parse().ok())
+                    .ok_or_else(invalid)?;
+                Ok(UnresolvedFlagGeometry::Grid(
+                    Rc::new(across.to_flag_geometry_in(dialect)?),
+                    Rc::new(down.to_flag_geometry_in(dialect)?),
+                    mode,
+                ))
+            }
+            ("mask", [shape, content]) => Ok(UnresolvedFlagGeometry::Mask(
+                Rc::new(shape.to_flag_geometry_in(dialect)?),
+                Rc::new(content.to_flag_geometry_in(dialect)?),
+            )),
+            ("include", [path]) => {
+                let path = path.literal().ok_or_else(invalid)?.to_string();
+                Ok(UnresolvedFlagGeometry::Include(path))
+            }
+            // Known operators with the wrong arguments
+            ("s", _)
+            | ("rays", _)
+            | ("disc", _)
+            | ("tilefill", _)
+            | ("t", _)
+            | ("r", _)
+            | ("tint", _)
+            | ("emblem", _)
+            | ("mask", _)
+            | ("grid", _)
+            | ("fimb", _)
+            | ("alpha", _)
+            | ("include", _) => Err(invalid()),
+            _ => Err(FlagError::UnknownOperator(written.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for SExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SExpr::List(list) => {
+                write!(f, "(")?;
+                for (i, expr) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", expr)?;
+                }
+                write!(f, ")")
+            }
+            SExpr::Literal(literal)
+                if literal.is_empty()
+                    || literal
+                        .chars()
+                        .any(|c| is_blank(c) || c == '(' || c == ')' || c == '"') =>
+            {
+                write!(f, "\"{}\"", literal)
+            }
+            SExpr::Literal(literal) => write!(f, "{}", literal),
+        }
+    }
+}
+
+/// Writes an expression with as few spaces as it can be parsed back from,
+/// which is only between a literal and whatever follows it, and with hex
+/// colors shortened to a known color code or, failing that, left bare.
+fn write_minimal(sexpr: &SExpr, out: &mut String) {
+    match sexpr {
+        SExpr::List(list) => {
+            out.push('(');
+            for (i, expr) in list.iter().enumerate() {
+                if i > 0 && list[i - 1].literal().is_some() {
+                    out.push(' ');
+                }
+                write_minimal(expr, out);
+            }
+            out.push(')');
+        }
+        SExpr::Literal(literal) => match literal.strip_prefix('#') {
+            Some(hex) => {
+                let rgb = to_hex_color(hex).map(Rgb);
+                match known_colors()
+                    .into_iter()
+                    .find(|(_, known)| *known != Color::None && Some(known.to_rgb()) == rgb)
+                {
+                    Some((code, _)) => out.push_str(code),
+                    None => out.push_str(hex),
+                }
+            }
+            None => out.push_str(&sexpr.to_string()),
+        },
+    }
+}
+
+/// Longer names for the operators, for those who prefer FDL that reads out.
+const OPERATOR_ALIASES: &[(&str, &str)] = &[
+    ("horizontal", "h"),
+    ("vertical", "v"),
+    ("solid", "s"),
+    ("tag", "t"),
+    ("ref", "r"),
+];
+
+/// Extra words that FDL may be written with: aliases for the operators on
+/// top of the built in long names, and color names on top of the built in
+/// colors.
+#[derive(Clone, Debug, Default)]
+pub struct Dialect {
+    aliases: HashMap<String, String>,
+    palette: HashMap<String, Color>,
+}
+
+impl Dialect {
+    /// Lets `alias` be written in place of the operator `operator`, which
+    /// may itself be a built in long name.
+    pub fn add_alias(&mut self, alias: &str, operator: &str) {
+        let operator = self.operator(operator).to_string();
+        self.aliases.insert(alias.to_string(), operator);
+    }
+
+    /// Lets `name` be written in place of `color`.
+    pub fn add_color(&mut self, name: &str, color: Color) {
+        self.palette.insert(name.to_string(), color);
+    }
+
+    /// The operator that `word` stands for.
+    fn operator<'a>(&'a self, word: &'a str) -> &'a str {
+        if let Some(operator) = self.aliases.get(word) {
+            return operator;
+        }
+        OPERATOR_ALIASES
+            .iter()
+            .find(|(alias, _)| *alias == word)
+            .map_or(word, |(_, operator)| operator)
+    }
+}
+
+/// The ways in which an S-expression can fail to describe a flag.
+#[derive(Debug, PartialEq)]
+pub enum FlagError {
+    /// A literal where a list describing a geometry was expected.
+    ExpectedList(String),
+    /// A split that isn't a weight followed by exactly two geometries.
+    MalformedSplit(String),
+    /// Something that is neither a known color code nor a hex color.
+    BadColor(String),
+    /// A list starting with something other than a known operator.
+    UnknownOperator(String),
+    /// A fragment that includes itself, directly or through others.
+    IncludeCycle(String),
+    /// Fragments nested more than `MAX_INCLUDE_DEPTH` levels deep.
+    IncludeTooDeep(String),
+    /// A fragment that couldn't be loaded.
+    IncludeNotFound(String),
+    /// A reference to a tag that is never defined.
+    UnresolvedReference(String),
+    /// An emblem image that couldn't be loaded.
+    EmblemNotFound(String),
+    /// Any other expression that doesn't describe a flag.
+    InvalidExpression(String),
+    /// An image smaller than the given width and height, which a flag needs
+    /// for every part to be seen.
+    TooSmall(u32, u32),
+}
+
+impl std::fmt::Display for FlagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FlagError::ExpectedList(expr) => {
+                write!(f, "expected a list describing a geometry, found {}", expr)
+            }
+            FlagError::MalformedSplit(expr) => write!(
+                f,
+                "malformed split {}, expected a weight followed by two geometries",
+                expr
+            ),
+            FlagError::BadColor(color) => write!(f, "unknown color {}", color),
+            FlagError::UnknownOperator(op) => write!(f, "unknown operator {}", op),
+            FlagError::IncludeCycle(path) => write!(f, "{} includes itself", path),
+            FlagError::IncludeTooDeep(path) => {
+                write!(f, "{} is nested too deeply within includes", path)
+            }
+            FlagError::IncludeNotFound(path) => write!(f, "could not load {}", path),
+            FlagError::UnresolvedReference(tag) => write!(f, "no tag named {}", tag),
+            FlagError::EmblemNotFound(path) => write!(f, "could not load emblem {}", path),
+            FlagError::InvalidExpression(expr) => write!(f, "invalid expression {}", expr),
+            FlagError::TooSmall(width, height) => {
+                write!(f, "the flag needs at least {}x{} pixels", width, height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlagError {}
+
+/// Parses a split weight, which may carry an optional `%` suffix. Only plain
+/// decimal numbers with `.` as the separator are accepted, so there is no
+/// room for locale specific forms like `1,5` or `1,000`, nor for exponents or
+/// infinities.
+fn parse_weight(literal: &str) -> Option<f32> {
+    let number = literal.strip_suffix('%').unwrap_or(literal);
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        return None;
+    }
+
+    number.parse().ok()
+}
+
+/// Parses the weight of a split, which is either a percentage as accepted by
+/// `parse_weight` or a fixed size such as `20px`. A negative size such as
+/// `-20px` fixes the second part instead of the first.
+fn parse_pivot(literal: &str) -> Option<Weight> {
+    let pixels = match literal.strip_suffix("px") {
+        Some(pixels) => pixels,
+        None => return parse_weight(literal).map(Weight::Percent),
+    };
+    let (after, digits) = match pixels.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, pixels),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let pixels = digits.parse().ok()?;
+    Some(if after {
+        Weight::PixelsAfter(pixels)
+    } else {
+        Weight::Pixels(pixels)
+    })
+}
+
+/// How many levels of `include` may be nested within each other.
+pub const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Loads the image for an `emblem` from the file system.
+fn load_emblem(path: &str) -> Result<RgbaImage, FlagError> {
+    use image::GenericImageView;
+
+    let img = image::open(path).map_err(|_| FlagError::EmblemNotFound(path.to_string()))?;
+    Ok(RgbaImage::from_fn(img.width(), img.height(), |x, y| {
+        img.get_pixel(x, y)
+    }))
+}
+
+/// Loads fragments for `include` from the file system.
+pub fn read_fragment(path: &str) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+/// Parses and resolves a flag written in the Flag Definition Language,
+/// reading any included fragments from disk.
+pub fn parse_flag(fdl: &str) -> Result<FlagGeometry, FlagError> {
+    parse_flag_with_palette(fdl, &HashMap::new())
+}
+
+/// Parses and resolves a flag like `parse_flag`, taking colors that aren't
+/// built in from `palette`. Included fragments only see the built in colors.
+pub fn parse_flag_with_palette(
+    fdl: &str,
+    palette: &HashMap<String, Color>,
+) -> Result<FlagGeometry, FlagError> {
+    let dialect = Dialect {
+        palette: palette.clone(),
+        ..Dialect::default()
+    };
+    parse_flag_in(fdl, &dialect)
+}
+
+/// Parses and resolves a flag like `parse_flag`, in the given dialect.
+/// Included fragments are always read in the default dialect.
+pub fn parse_flag_in(fdl: &str, dialect: &Dialect) -> Result<FlagGeometry, FlagError> {
+    parse_unresolved_in(fdl, dialect)?.resolve_all()
+}
+
+/// Parses a flag in the given dialect and splices in any included fragments,
+/// leaving references to tags for the caller to resolve.
+fn parse_unresolved_in(fdl: &str, dialect: &Dialect) -> Result<UnresolvedFlagGeometry, FlagError> {
+    SExpr::parse(&mut fdl.chars().peekable())
+        .ok_or_else(|| FlagError::InvalidExpression(fdl.trim().to_string()))?
+        .to_flag_geometry_in(dialect)?
+        .expand_includes(&mut read_fragment)
+}
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_INVALID_FLAG: i32 = 2;
+const EXIT_IO: i32 = 3;
+
+const USAGE: &str = "usage:
+    flagrant render [--ratio w:h] [--tag name] [--flip-h] [--flip-v] [--reverse [--keep tag]...] <fdl> [out.png]
+    flagrant render [--ratio w:h] [--out-prefix out | --out-template name] <fdl> <fdl>...
+    flagrant validate <fdl>
+    flagrant colors
+    flagrant svg [--ratio w:h] <fdl> [out.svg]";
+
+/// The height of rendered output. The width follows from the aspect ratio.
+const OUTPUT_HEIGHT: u32 = 300;
+
+/// Derives output dimensions from an aspect ratio written as `w:h`, such as
+/// `3:2` or `1:1` for square flags.
+fn ratio_dimensions(ratio: &str) -> Option<(u32, u32)> {
+    let mut parts = ratio.splitn(2, ':');
+    let w = parts.next()?.trim().parse::<u32>().ok()?;
+    let h = parts.next()?.trim().parse::<u32>().ok()?;
+    if w == 0 || h == 0 {
+        return None;
+    }
+    let width = (OUTPUT_HEIGHT as f32 * w as f32 / h as f32).round() as u32;
+    Some((width.max(1), OUTPUT_HEIGHT))
+}
+
+/// Reads a whole FDL from `reader`, such as standard input or a named pipe,
+/// until it runs out. Nothing is assumed about how much there is or how it
+/// arrives, so short reads and reads interrupted by signals are carried on.
+fn read_fdl<R: Read>(mut reader: R) -> std::io::Result<String> {
+    let mut fdl = String::new();
+    reader.read_to_string(&mut fdl)?;
+    Ok(fdl)
+}
+
+/// The position of a `-` standing for a flag read from standard input, once
+/// the options have been taken out. Only the first FDL is read this way,
+/// so a `-` anywhere else, such as for the output, is taken as it is.
+fn stdin_argument(args: &[&str]) -> Option<usize> {
+    let index = match args.first() {
+        Some(&"render") | Some(&"validate") | Some(&"svg") => 1,
+        _ => 0,
+    };
+    Some(index).filter(|&index| args.get(index) == Some(&"-"))
+}
+
+/// Runs the command line interface on the given arguments (excluding the
+/// program name) and returns the process exit code.
+fn run(args: &[String]) -> i32 {
+    let mut args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let (size, template, tag) = match (
+        take_option(&mut args, "--ratio"),
+        take_option(&mut args, "--out-prefix"),
+        take_option(&mut args, "--out-template"),
+        take_option(&mut args, "--tag"),
+    ) {
+        (Ok(ratio), Ok(prefix), Ok(template), Ok(tag)) => {
+            let template = match (prefix, template) {
+                (Some(_), Some(_)) => {
+                    eprintln!("{}", USAGE);
+                    return EXIT_USAGE;
+                }
+                (Some(prefix), None) => Some(format!("{}-{{index}}.png", prefix)),
+                (None, template) => template.map(str::to_string),
+            };
+            match ratio.map(ratio_dimensions) {
+                None => ((400, 300), template, tag),
+                Some(Some(size)) => (size, template, tag),
+                Some(None) => {
+                    eprintln!("{}", USAGE);
+                    return EXIT_USAGE;
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut keep = Vec::new();
+    loop {
+        match take_option(&mut args, "--keep") {
+            Ok(Some(tag)) => keep.push(tag),
+            Ok(None) => break,
+            Err(()) => {
+                eprintln!("{}", USAGE);
+                return EXIT_USAGE;
+            }
+        }
+    }
+    let options = RenderOptions {
+        size,
+        tag,
+        flip_h: take_switch(&mut args, "--flip-h"),
+        flip_v: take_switch(&mut args, "--flip-v"),
+        reverse: take_switch(&mut args, "--reverse"),
+        keep,
+    };
+
+    let stdin_fdl;
+    if let Some(index) = stdin_argument(&args) {
+        stdin_fdl = match read_fdl(std::io::stdin().lock()) {
+            Ok(fdl) => fdl,
+            Err(err) => {
+                eprintln!("could not read standard input: {}", err);
+                return EXIT_IO;
+            }
+        };
+        args[index] = &stdin_fdl;
+    }
+
+    match args.as_slice() {
+        ["render", fdls @ ..]
+            if (fdls.len() > 1 || template.is_some()) && fdls.iter().all(|fdl| is_fdl(fdl)) =>
+        {
+            render_batch(
+                fdls,
+                template.as_deref().unwrap_or("out-{index}.png"),
+                &options,
+            )
+        }
+        ["render", fdl] => render_command(fdl, "out.png", &options),
+        ["render", fdl, out] => render_command(fdl, out, &options),
+        ["validate", fdl] => match parse_flag(fdl) {
+            Ok(_) => 0,
+            Err(err) => report_invalid_flag(&err, &mut std::io::stderr()),
+        },
+        ["colors"] | ["--list-colors"] => {
+            for (code, color) in known_colors() {
+                match color {
+                    Color::None => println!("{}\ttransparent", code),
+                    _ => {
+                        let Rgb([r, g, b]) = color.to_rgb();
+                        println!("{}\t#{:02x}{:02x}{:02x}", code, r, g, b);
+                    }
+                }
+            }
+            0
+        }
+        ["svg", fdl] => svg_command(fdl, "out.svg", size),
+        ["svg", fdl, out] => svg_command(fdl, out, size),
+        // Before subcommands existed, the flag was the only argument
+        [fdl] if is_fdl(fdl) => render_command(fdl, "out.png", &options),
+        _ => {
+            eprintln!("{}", USAGE);
+            EXIT_USAGE
+        }
+    }
+}
+
+/// Tells the user why their flag is invalid, returning the exit code for it.
+fn report_invalid_flag<W: Write>(err: &FlagError, out: &mut W) -> i32 {
+    // There is nowhere left to report a failure to report
+    let _ = writeln!(out, "invalid flag: {}", err);
+    EXIT_INVALID_FLAG
+}
+
+/// Removes the option `name` and the value following it from `args`,
+/// returning the value. A missing value is an error.
+fn take_option<'a>(args: &mut Vec<&'a str>, name: &str) -> Result<Option<&'a str>, ()> {
+    let i = match args.iter().position(|&arg| arg == name) {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let value = *args.get(i + 1).ok_or(())?;
+    args.drain(i..i + 2);
+    Ok(Some(value))
+}
+
+/// Removes the option `name`, which takes no value, from `args`, returning
+/// whether it was there.
+fn take_switch(args: &mut Vec<&str>, name: &str) -> bool {
+    let len = args.len();
+    args.retain(|&arg| arg != name);
+    args.len() != len
+}
+
+/// Whether a command line argument is a flag rather than a file name.
+fn is_fdl(arg: &str) -> bool {
+    arg.trim_start().starts_with('(')
+}
+
+/// Fills in the placeholders of an output file name template for the flag at
+/// `index` in a batch, which uses `colors`. `{index}` is the position of the
+/// flag and `{colors}` its colors joined by `-`, as in `r-w`. Anything else in
+/// braces is an error, which carries the placeholder.
+fn expand_template(template: &str, index: usize, colors: &[Color]) -> Result<String, String> {
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| rest[start..].to_string())?
+            + start;
+        match &rest[start + 1..end] {
+            "index" => name.push_str(&index.to_string()),
+            "colors" => {
+                let codes = colors
+                    .iter()
+                    .filter_map(|color| Some(color.to_fdl()?.trim_start_matches('#').to_string()))
+                    .collect::<Vec<_>>();
+                name.push_str(&codes.join("-"));
+            }
+            _ => return Err(rest[start..=end].to_string()),
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// Renders each flag to the file named by `template`, as filled in by
+/// `expand_template`, carrying on past flags that fail. The exit code is that
+/// of the most serious failure.
+fn render_batch(fdls: &[&str], template: &str, options: &RenderOptions) -> i32 {
+    if let Err(placeholder) = expand_template(template, 0, &[]) {
+        eprintln!("unknown placeholder {} in {}", placeholder, template);
+        return EXIT_USAGE;
+    }
+
+    let mut status = 0;
+    for (i, fdl) in fdls.iter().enumerate() {
+        // A flag that doesn't parse is reported by `render_command` instead
+        let colors = parse_flag(fdl).map(|geo| geo.colors()).unwrap_or_default();
+        let out = expand_template(template, i, &colors).expect("template already checked");
+        let code = render_command(fdl, &out, options);
+        if code != 0 {
+            eprintln!("flag {} failed", i);
+            status = status.max(code);
+        }
+    }
+    status
+}
+
+/// How `render` draws each flag.
+struct RenderOptions<'a> {
+    size: (u32, u32),
+    /// Renders just the part of the flag under this tag, filling the whole
+    /// image.
+    tag: Option<&'a str>,
+    /// Mirrors the flag left to right, tags and all.
+    flip_h: bool,
+    /// Mirrors the flag top to bottom, as when flown upside down.
+    flip_v: bool,
+    /// Shows the reverse side, as by `UnresolvedFlagGeometry::reverse_side`.
+    reverse: bool,
+    /// The tags that keep their orientation on the reverse side.
+    keep: Vec<&'a str>,
+}
+
+fn render_command(fdl: &str, out: &str, options: &RenderOptions) -> i32 {
+    let flag = parse_unresolved_in(fdl, &Dialect::default()).and_then(|ufg| {
+        let ufg = if options.reverse {
+            ufg.reverse_side(&options.keep)
+        } else {
+            ufg
+        };
+        match options.tag {
+            None => ufg.resolve_all(),
+            Some(tag) => ufg.resolve_tagged(tag),
+        }
+    });
+    let flag = match flag {
+        Ok(flag) => flag,
+        Err(err) => return report_invalid_flag(&err, &mut std::io::stderr()),
+    };
+
+    let flag = if options.flip_h {
+        flag.mirror_horizontal()
+    } else {
+        flag
+    };
+    let flag = if options.flip_v {
+        flag.mirror_vertical()
+    } else {
+        flag
+    };
+
+    eprintln!("{:#?}", flag);
+
+    let (width, height) = options.size;
+    let img = render(&flag, width, height);
+    match save_image(&img, out, fdl) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("failed to write {}: {}", out, e);
+            EXIT_IO
+        }
+    }
+}
+
+/// Saves an image in the format implied by the extension of `path`.
+fn save_image(img: &RgbImage, path: &str, fdl: &str) -> Result<(), String> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+
+    let format = ImageFormat::from_path(path)
+        .map_err(|_| format!("unsupported output format .{}", extension))?;
+    if format == ImageFormat::WebP {
+        // The image crate can decode WebP but has no encoder for it
+        return Err(format!("writing .{} is not supported", extension));
+    }
+
+    if format == ImageFormat::Png {
+        // Remember the source, so the flag can be recreated from the image
+        let png = encode_png(img).map_err(|e| e.to_string())?;
+        let png = png_with_text(&png, FDL_KEYWORD, fdl).unwrap_or(png);
+        return std::fs::write(path, png).map_err(|e| e.to_string());
+    }
+
+    img.save_with_format(path, format)
+        .map_err(|e| e.to_string())
+}
+
+fn svg_command(fdl: &str, out: &str, (width, height): (u32, u32)) -> i32 {
+    let mut svg = SvgCanvas::new(width, height);
+    let drawn =
+        parse_unresolved_in(fdl, &Dialect::default()).and_then(|ufg| ufg.draw_svg(&mut svg));
+    if let Err(err) = drawn {
+        return report_invalid_flag(&err, &mut std::io::stderr());
+    }
+
+    match std::fs::File::create(out).and_then(|file| svg.write(file)) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("failed to write {}: {}", out, e);
+            EXIT_IO
+        }
+    }
+}
+
+fn main() {
+    let args = args().skip(1).collect::<Vec<_>>();
+    std::process::exit(run(&args));
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn flag(fdl: &str) -> FlagGeometry {
+        parse_flag(fdl).unwrap()
+    }
+
+    #[test]
+    fn test_sexpr_parse() {
+        println!(
+            "{:#?}",
+            SExpr::parse(&mut "(h 33 (s b) (h 50 (s w) (s r)))".chars().peekable()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_percent_weights() {
+        let img = render(&flag("(h 50% (s r) 50% (s w))"), 10, 2);
+        assert_eq!(img, render(&flag("(h 50 (s r) (s w))"), 10, 2));
+        assert_eq!(img[(4, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(5, 0)], Color::White.to_rgb());
+        assert_eq!(img, render(&flag("(h 50% (s r) (s w))"), 10, 2));
+
+        // Weights are shares of their sum, whether written with `%` or not
+        assert_eq!(flag("(h 50% (s r) 50 (s w))"), flag("(h 50 (s r) (s w))"));
+        assert_eq!(flag("(h 1 (s r) 3% (s w))"), flag("(h 25 (s r) (s w))"));
+        assert_eq!(
+            flag("(v 2 (s r) 1 (s w))"),
+            flag("(v 66.666664 (s r) (s w))")
+        );
+    }
+
+    #[test]
+    fn test_fractional_weights() {
+        // 37.5% of 8 pixels is exactly 3, which no integer percentage gives
+        let img = render(&flag("(h 37.5 (s r) (s w))"), 8, 1);
+        for x in 0..8 {
+            let expected = if x < 3 { Color::Red } else { Color::White };
+            assert_eq!(img[(x, 0)], expected.to_rgb());
+        }
+
+        // The second half always fills whatever the first one left over
+        let img = render(&flag("(v 33.3 (s r) (s w))"), 1, 7);
+        assert_eq!(img[(0, 1)], Color::Red.to_rgb());
+        assert_eq!(img[(0, 2)], Color::White.to_rgb());
+        assert_eq!(img[(0, 6)], Color::White.to_rgb());
+    }
+
+    #[test]
+    fn test_resolve_all() {
+        let img = render(&flag("(h 50 (t stripe (s r)) (r stripe))"), 4, 1);
+        assert!(img.pixels().all(|p| *p == Color::Red.to_rgb()));
+    }
+
+    #[test]
+    fn test_unresolved_reference() {
+        let fdl = "(h 50 (s r) (r missing))";
+        let ufg = SExpr::parse(&mut fdl.chars().peekable())
+            .unwrap()
+            .to_flag_geometry()
+            .unwrap();
+        assert_eq!(
+            ufg.resolve_all(),
+            Err(FlagError::UnresolvedReference("missing".to_string()))
+        );
+        assert!(parse_flag(fdl).is_err());
+    }
+
+    #[test]
+    fn test_render_dynamic() {
+        use image::GenericImageView;
+
+        let img = render_dynamic(&flag("(v 50 (s b) (s y))"), 40, 30);
+        assert_eq!(img.dimensions(), (40, 30));
+    }
+
+    #[test]
+    fn test_draw_into() {
+        let mut img = RgbImage::new(4, 2);
+        flag("(s r)").draw_into(&mut img, 0, 0, 2, 2);
+        flag("(v 50 (s w) (s b))").draw_into(&mut img, 2, 0, 2, 2);
+
+        assert_eq!(img[(1, 1)], Color::Red.to_rgb());
+        assert_eq!(img[(2, 0)], Color::White.to_rgb());
+        assert_eq!(img[(3, 1)], Color::Blue.to_rgb());
+    }
+
+    #[test]
+    fn test_none_color() {
+        let mut img = render(&flag("(s r)"), 2, 2);
+        flag("(v 50 (s none) (s b))").draw(&mut img);
+
+        assert_eq!(img[(0, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(1, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(0, 1)], Color::Blue.to_rgb());
+    }
+
+    #[test]
+    fn test_tag_shadowing() {
+        let img = render(&flag("(h 50 (t a (s r)) (h 50 (t a (s w)) (r a)))"), 4, 1);
+        assert_eq!(img[(3, 0)], Color::White.to_rgb());
+
+        let img = render(&flag("(h 50 (t a (v 50 (t a (s g)) (s b))) (r a))"), 2, 1);
+        assert_eq!(img[(1, 0)], Color::Green.to_rgb());
+    }
+
+    #[test]
+    fn test_render_fit_contain() {
+        let geo = flag("(h 25 (s b) (s r))");
+        let img = render_fit(&geo, 20, 20, 2.0, FitMode::Contain, Color::White);
+
+        assert_eq!(img[(10, 0)], Color::White.to_rgb());
+        assert_eq!(img[(10, 19)], Color::White.to_rgb());
+        assert_eq!(img[(0, 10)], Color::Blue.to_rgb());
+        assert_eq!(img[(19, 10)], Color::Red.to_rgb());
+    }
+
+    #[test]
+    fn test_render_fit_cover() {
+        let geo = flag("(h 25 (s b) (s r))");
+        let img = render_fit(&geo, 20, 20, 2.0, FitMode::Cover, Color::White);
+
+        // The blue quarter is cropped away entirely
+        assert!(img.pixels().all(|p| *p == Color::Red.to_rgb()));
+    }
+
+    #[test]
+    fn test_rays() {
+        let img = render(&flag("(rays 4 r y (s b))"), 100, 100);
+
+        assert_eq!(img[(50, 50)], Color::Red.to_rgb());
+        for k in 0..8 {
+            let angle = (k as f32 + 0.5) * std::f32::consts::PI / 4.0;
+            let x = (50.0 + 40.0 * angle.cos()) as u32;
+            let y = (50.0 + 40.0 * angle.sin()) as u32;
+            let expected = if k % 2 == 0 {
+                Color::Yellow
+            } else {
+                Color::Blue
+            };
+            assert_eq!(img[(x, y)], expected.to_rgb(), "sector {}", k);
+        }
+    }
+
+    #[test]
+    fn test_disc() {
+        let img = render(&flag("(disc 50 50 30 r (s w))"), 60, 40);
+
+        assert_eq!(img[(30, 20)], Color::Red.to_rgb());
+        assert_eq!(img[(30, 9)], Color::Red.to_rgb());
+        assert_eq!(img[(30, 7)], Color::White.to_rgb());
+        for &(x, y) in &[(0, 0), (59, 0), (0, 39), (59, 39)] {
+            assert_eq!(img[(x, y)], Color::White.to_rgb());
+        }
+    }
+
+    #[test]
+    fn test_tag_names() {
+        let img = render(&flag("(h 50 (t stripe (s r)) (r stripe))"), 2, 1);
+        assert_eq!(img[(1, 0)], Color::Red.to_rgb());
+
+        let img = render(
+            &flag("(h 50 (t \"blue stripe\" (s b)) (r \" blue stripe \"))"),
+            2,
+            1,
+        );
+        assert_eq!(img[(1, 0)], Color::Blue.to_rgb());
+
+        assert!(SExpr::parse(&mut "(t \"open (s b))".chars().peekable()).is_none());
+    }
+
+    #[test]
+    fn test_svg_canvas() {
+        let mut svg = SvgCanvas::new(4, 2);
+        flag("(h 50 (s r) (s none))").draw(&mut svg);
+
+        let mut out = Vec::new();
+        svg.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(r##"<rect x="0" y="0" width="2" height="2" fill="#ff0000"/>"##));
+        assert_eq!(out.matches("<rect").count(), 1);
+    }
+
+    #[test]
+    fn test_svg_tag_groups() {
+        let fdl =
+            "(v 50 (t \"top & bottom\" (h 50 (s r) (s b))) (v 50 (s w) (r \"top & bottom\")))";
+        let ufg = SExpr::parse(&mut fdl.chars().peekable())
+            .unwrap()
+            .to_flag_geometry()
+            .unwrap();
+        let mut svg = SvgCanvas::new(4, 4);
+        ufg.draw_svg(&mut svg).unwrap();
+
+        let mut out = Vec::new();
+        svg.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches(r#"<g id="top &amp; bottom">"#).count(), 1);
+        assert_eq!(out.matches("</g>").count(), 1);
+        assert!(out.contains(r##"    <rect x="0" y="0" width="2" height="2" fill="#ff0000"/>"##));
+
+        // The same rectangles as drawing the resolved flag, just grouped
+        let mut flat = SvgCanvas::new(4, 4);
+        ufg.resolve_all().unwrap().draw(&mut flat);
+        let trimmed = |svg: &SvgCanvas| {
+            svg.elements
+                .iter()
+                .map(|e| e.trim().to_string())
+                .filter(|e| e.starts_with("<rect"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(trimmed(&svg), trimmed(&flat));
+    }
+
+    #[test]
+    fn test_diffuse_lengths() {
+        let sizes = diffuse_lengths(vec![100.0 / 7.0; 7], 100);
+        assert_eq!(sizes.iter().sum::<u32>(), 100);
+        assert!(sizes.iter().all(|&size| size == 14 || size == 15));
+        assert_eq!(sizes.iter().filter(|&&size| size == 15).count(), 2);
+
+        assert_eq!(
+            diffuse_lengths(std::iter::repeat(30.0), 100),
+            vec![30, 30, 30, 10]
+        );
+        assert!(diffuse_lengths(vec![10.0], 0).is_empty());
+
+        // Seven equal parts written as nested splits, each a share of what
+        // the ones before it left over
+        let parts = flag("(h 14.285714 (s r) (h 16.666666 (s w) (h 20 (s r) (h 25 (s w) (h 33.333332 (s r) (h 50 (s w) (s r)))))))");
+        let widths = parts
+            .leaves(100, 1)
+            .iter()
+            .map(|(rect, _)| rect.width)
+            .collect::<Vec<_>>();
+        assert_eq!(widths, vec![14, 15, 14, 14, 14, 15, 14]);
+
+        // Uneven nested splits, where rounding each on its own would leave
+        // the last part nearly two pixels too wide
+        let weights = [13.0, 6.15, 11.98, 18.93, 2.68, 4.48, 8.97, 33.81];
+        let mut fdl = String::from("(s g)");
+        let mut rest = weights[weights.len() - 1];
+        for weight in weights.iter().rev().skip(1) {
+            rest += weight;
+            fdl = format!("(h {} (s r) {})", weight / rest * 100.0, fdl);
+        }
+        let total = weights.iter().sum::<f32>();
+        for (weight, (rect, _)) in weights.iter().zip(flag(&fdl).leaves(202, 1)) {
+            let ideal = weight / total * 202.0;
+            assert!(
+                (rect.width as f32 - ideal).abs() < 1.0,
+                "{} {}",
+                rect.width,
+                ideal
+            );
+        }
+
+        // Seven stripes over 100 pixels, without a sliver left at the end
+        let leaves = flag("(tilefill v 14.2857 r w)").leaves(10, 100);
+        assert_eq!(leaves.len(), 7);
+        assert!(leaves.iter().all(|(rect, _)| rect.height >= 14));
+        assert_eq!(leaves[6].1, Color::Red);
+    }
+
+    #[test]
+    fn test_clamp_rect() {
+        let bounds = Rect::new(0, 0, 100, 50);
+        assert_eq!(
+            clamp_rect(10, 10, 20, 20, bounds),
+            Rect::new(10, 10, 20, 20)
+        );
+        assert_eq!(clamp_rect(95, 45, 10, 10, bounds), Rect::new(95, 45, 5, 5));
+        assert_eq!(clamp_rect(-5, -8, 10, 10, bounds), Rect::new(0, 0, 5, 2));
+        assert_eq!(
+            clamp_rect(-10, 20, u32::MAX, u32::MAX, bounds),
+            Rect::new(0, 20, 100, 30)
+        );
+        assert!(clamp_rect(100, 0, 10, 10, bounds).is_empty());
+        assert!(clamp_rect(-20, 0, 10, 10, bounds).is_empty());
+
+        let inner = Rect::new(90, 40, 10, 10);
+        assert_eq!(
+            clamp_rect(i64::from(u32::MAX), 45, 10, 1, inner),
+            Rect::new(100, 45, 0, 1)
+        );
+
+        // A stroke far wider than the flag covers it without overflowing
+        let img = render_outlined(&flag("(h 50 (s r) (s w))"), 4, 4, 100, Color::Black);
+        assert!(img.pixels().all(|pixel| *pixel == Color::Black.to_rgb()));
+    }
+
+    #[test]
+    fn test_render_tile() {
+        let geo =
+            flag("(h 30 (rays 8 r y (s b)) (v 45 (disc 40 50 30 g (s w)) (tilefill h 7 r w)))");
+        let full = render(&geo, 60, 40);
+
+        let mut stitched = RgbImage::new(60, 40);
+        for (tile_x, tile_y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let tile = render_tile(&geo, 60, 40, tile_x, tile_y, 32);
+            let expected = (
+                if tile_x == 0 { 32 } else { 28 },
+                if tile_y == 0 { 32 } else { 8 },
+            );
+            assert_eq!(tile.dimensions(), expected);
+            for (x, y, pixel) in tile.enumerate_pixels() {
+                stitched[(tile_x * 32 + x, tile_y * 32 + y)] = *pixel;
+            }
+        }
+        assert!(stitched == full);
+
+        assert_eq!(render_tile(&geo, 60, 40, 2, 0, 32).dimensions(), (0, 32));
+    }
+
+    #[test]
+    fn test_render_with_legend() {
+        let geo = flag("(h 33 (s b) (h 50 (s w) (s none)))");
+        let img = render_with_legend(&geo, 90, 60);
+        assert_eq!(img.dimensions(), (90 + 15, 60));
+        assert_eq!(img[(10, 10)], Color::Blue.to_rgb());
+        assert_eq!(img[(89, 10)], Color::Black.to_rgb());
+
+        // Swatches of 13 pixels with a margin of 1, skipping the transparent
+        for (i, color) in [Color::Blue, Color::White].iter().enumerate() {
+            let top = 1 + i as u32 * 14;
+            assert_eq!(img[(91, top)], color.to_rgb());
+            assert_eq!(img[(103, top + 12)], color.to_rgb());
+        }
+        assert_eq!(img[(90, 0)], Color::White.to_rgb());
+        assert_eq!(img[(95, 30)], Color::White.to_rgb());
+    }
+
+    #[test]
+    fn test_render_outlined() {
+        let img = render_outlined(&flag("(h 50 (s r) (s w))"), 10, 4, 2, Color::Black);
+        for y in 0..4 {
+            assert_eq!(img[(3, y)], Color::Red.to_rgb());
+            assert_eq!(img[(4, y)], Color::Black.to_rgb());
+            assert_eq!(img[(5, y)], Color::Black.to_rgb());
+            assert_eq!(img[(6, y)], Color::White.to_rgb());
+        }
+        assert_eq!(img[(0, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(9, 3)], Color::White.to_rgb());
+
+        // Stripes of the same color aren't separated
+        let img = render_outlined(
+            &flag("(v 50 (s r) (h 50 (s r) (s b)))"),
+            4,
+            4,
+            1,
+            Color::Yellow,
+        );
+        assert_eq!(img[(0, 2)], Color::Red.to_rgb());
+        assert_eq!(img[(2, 2)], Color::Yellow.to_rgb());
+        assert_eq!(img[(3, 2)], Color::Yellow.to_rgb());
+        assert_eq!(img[(3, 1)], Color::Red.to_rgb());
+        assert_eq!(img[(3, 3)], Color::Blue.to_rgb());
+    }
+
+    #[test]
+    fn test_symmetry() {
+        let all = Symmetry {
+            horizontal: true,
+            vertical: true,
+            rotational: true,
+        };
+        let bar = "(h 50 (h 80 (s r) (s w)) (h 20 (s w) (s r)))";
+        let cross = format!("(v 50 (v 80 {0} (s w)) (v 20 (s w) {0}))", bar);
+        assert_eq!(flag(&cross).symmetry(), all);
+
+        let bar = "(h 30 (s b) (h 14.5 (s y) (s b)))";
+        let nordic = format!("(v 50 (v 80 {0} (s y)) (v 20 (s y) {0}))", bar);
+        assert_eq!(
+            flag(&nordic).symmetry(),
+            Symmetry {
+                horizontal: true,
+                ..Symmetry::default()
+            }
+        );
+
+        assert_eq!(flag("(disc 50 50 30 r (s w))").symmetry(), all);
+
+        // Even numbers of rays look the same turned half way round, and odd
+        // ones from either side
+        assert_eq!(
+            flag("(rays 4 y r (s w))").symmetry(),
+            Symmetry {
+                rotational: true,
+                ..Symmetry::default()
+            }
+        );
+        assert_eq!(
+            flag("(rays 3 y r (s w))").symmetry(),
+            Symmetry {
+                vertical: true,
+                ..Symmetry::default()
+            }
+        );
+        assert_eq!(
+            flag("(h 50 (v 50 (s r) (s w)) (v 50 (s w) (s r)))").symmetry(),
+            Symmetry {
+                rotational: true,
+                ..Symmetry::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_mirror_rays() {
+        // Flipped top to bottom, rays and field trade places, and so they do
+        // left to right unless a ray points straight along the axis
+        let flipped = |img: &RgbImage, horizontal: bool| {
+            RgbImage::from_fn(img.width(), img.height(), |x, y| match horizontal {
+                true => img[(img.width() - 1 - x, y)],
+                false => img[(x, img.height() - 1 - y)],
+            })
+        };
+        for count in &[3, 4, 5, 8] {
+            let geo = flag(&format!("(rays {} y r (h 30 (s w) (s b)))", count));
+            let img = render(&geo, 40, 30);
+            assert_eq!(
+                render(&geo.mirror_horizontal(), 40, 30),
+                flipped(&img, true)
+            );
+            assert_eq!(render(&geo.mirror_vertical(), 40, 30), flipped(&img, false));
+        }
+    }
+
+    #[test]
+    fn test_mirror_tilefill() {
+        // Three stripes of three pixels and one left over, so the red and
+        // white ends don't match
+        let tiles = flag("(tilefill h 30 r w)");
+        let row = |img: &RgbImage| (0..10).map(|x| img[(x, 0)]).collect::<Vec<_>>();
+        let (r, w) = (Color::Red.to_rgb(), Color::White.to_rgb());
+        assert_eq!(
+            row(&render(&tiles, 10, 2)),
+            vec![r, r, r, w, w, w, r, r, r, w]
+        );
+        assert_eq!(
+            row(&render(&tiles.mirror_horizontal(), 10, 2)),
+            vec![w, r, r, r, w, w, w, r, r, r]
+        );
+        assert_eq!(tiles.mirror_horizontal().mirror_horizontal(), tiles);
+
+        // Flipped along the stripes, nothing changes
+        assert_eq!(tiles.mirror_vertical(), tiles);
+        assert_eq!(
+            tiles.symmetry(),
+            Symmetry {
+                horizontal: true,
+                ..Symmetry::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cached_flag() {
+        let mut cached = CachedFlag::new(flag("(h 50 (s r) (s w))"));
+        let first = cached.render(4, 2).as_raw().as_ptr();
+        assert_eq!(cached.render(4, 2).as_raw().as_ptr(), first);
+
+        let img = cached.render(6, 2);
+        assert_eq!(img.dimensions(), (6, 2));
+        assert_eq!(img[(2, 0)], Color::Red.to_rgb());
+
+        cached.set_geometry(flag("(s b)"));
+        assert_eq!(cached.render(6, 2)[(2, 0)], Color::Blue.to_rgb());
+    }
+
+    #[test]
+    fn test_fimbriation() {
+        let img = render(&flag("(fimb 4 w (h 50 (s r) (s b)))"), 20, 2);
+        let row = (0..20).map(|x| img[(x, 1)]).collect::<Vec<_>>();
+        let (red, white, blue) = (
+            Color::Red.to_rgb(),
+            Color::White.to_rgb(),
+            Color::Blue.to_rgb(),
+        );
+        assert_eq!(row[..8], [red; 8]);
+        assert_eq!(row[8..12], [white; 4]);
+        assert_eq!(row[12..], [blue; 8]);
+
+        // Every stripe of a tricolor is set off, but not the split inside one
+        let geo = flag("(fimb 2 y (v 33 (s r) (v 50 (h 50 (s w) (s b)) (s g))))");
+        let colors = geo
+            .leaves(10, 30)
+            .into_iter()
+            .filter(|(rect, _)| rect.left == 0)
+            .map(|(rect, color)| (rect.top, rect.height, color))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            colors,
+            vec![
+                (0, 9, Color::Red),
+                (9, 1, Color::Yellow),
+                (10, 1, Color::Yellow),
+                (11, 9, Color::White),
+                (20, 1, Color::Yellow),
+                (21, 1, Color::Yellow),
+                (22, 8, Color::Green),
+            ]
+        );
+
+        assert_eq!(flag("(fimb 2 w (s r))"), flag("(s r)"));
+        assert!(parse_flag("(fimb -1 w (h 50 (s r) (s b)))").is_err());
+    }
+
+    #[test]
+    fn test_procedural() {
+        let checkers = FlagGeometry::procedural(|x, y| {
+            if (x / 2 + y / 2).is_multiple_of(2) {
+                Color::Red.to_rgb()
+            } else {
+                Color::White.to_rgb()
+            }
+        });
+        let geo = FlagGeometry::Horizontal(
+            Rc::new(FlagGeometry::Solid(Color::Blue)),
+            Rc::new(checkers.clone()),
+            Weight::Percent(50.0),
+        );
+        let img = render(&geo, 8, 4);
+        assert_eq!(img[(1, 1)], Color::Blue.to_rgb());
+        // Positions are relative to the area being filled
+        assert_eq!(img[(4, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(6, 0)], Color::White.to_rgb());
+        assert_eq!(img[(6, 2)], Color::Red.to_rgb());
+
+        assert_eq!(checkers, checkers.clone());
+        assert_ne!(checkers, FlagGeometry::procedural(|_, _| Rgb([0, 0, 0])));
+        let tinted = render(&checkers.tinted(&Color::Blue), 4, 4);
+        assert_eq!(tinted[(0, 0)], Color::Black.to_rgb());
+        assert_eq!(tinted[(2, 0)], Color::Blue.to_rgb());
+    }
+
+    #[test]
+    fn test_mirror_procedural() {
+        let gradient = FlagGeometry::procedural(|x, y| Rgb([x as u8 * 10, y as u8 * 10, 0]));
+
+        let img = render(&gradient.mirror_horizontal(), 8, 4);
+        assert_eq!(img[(0, 1)], Rgb([70, 10, 0]));
+        assert_eq!(img[(7, 3)], Rgb([0, 30, 0]));
+        let img = render(&gradient.mirror_vertical(), 8, 4);
+        assert_eq!(img[(0, 0)], Rgb([0, 30, 0]));
+        assert_eq!(img[(5, 3)], Rgb([50, 0, 0]));
+
+        // Mirrored inside a split, the fill is flipped within its own area
+        let geo = FlagGeometry::Horizontal(
+            Rc::new(FlagGeometry::Solid(Color::Blue)),
+            Rc::new(gradient.clone()),
+            Weight::Percent(50.0),
+        );
+        let img = render(&geo.mirror_horizontal(), 8, 4);
+        assert_eq!(img[(0, 0)], Rgb([30, 0, 0]));
+        assert_eq!(img[(3, 0)], Rgb([0, 0, 0]));
+        assert_eq!(img[(4, 0)], Color::Blue.to_rgb());
+
+        assert_eq!(gradient.mirror_horizontal().mirror_horizontal(), gradient);
+        assert_eq!(
+            gradient
+                .mirror_vertical()
+                .mirror_horizontal()
+                .mirror_vertical(),
+            gradient.mirror_horizontal()
+        );
+        assert_eq!(gradient.symmetry(), Symmetry::default());
+    }
+
+    /// Writes the flag out as FDL and parses it back, failing with the FDL
+    /// and `context` if it doesn't come back the same.
+    fn assert_round_trips(geo: &FlagGeometry, context: &str) {
+        let fdl = geo
+            .to_fdl()
+            .unwrap_or_else(|| panic!("{}: no FDL for {:?}", context, geo));
+        let parsed = parse_flag(&fdl);
+        assert_eq!(parsed.as_ref(), Ok(geo), "{}: {}", context, fdl);
+    }
+
+    #[test]
+    fn test_to_minimal_fdl() {
+        let geo = flag("(h 50 (v 30 (s r) (s r)) (alpha 1 (disc 50 50 30 #00ff00 (s #ff8000))))");
+        let minimal = geo.to_minimal_fdl().unwrap();
+        assert_eq!(minimal, "(h 50 (s r)(disc 50 50 30 g (s ff8000)))");
+        assert!(minimal.len() < geo.to_fdl().unwrap().len());
+        assert_eq!(render(&flag(&minimal), 40, 30), render(&geo, 40, 30));
+
+        assert_eq!(
+            flag("(v 100 (s b) (s w))").to_minimal_fdl().as_deref(),
+            Some("(s b)")
+        );
+        assert_eq!(flag("(tilefill h 10 y y)").simplify(), flag("(s y)"));
+        assert_eq!(flag("(disc 50 50 30 w (s w))").simplify(), flag("(s w)"));
+
+        for seed in 0..200 {
+            let geo = random_flag(seed, 6);
+            let minimal = geo.to_minimal_fdl().unwrap();
+            let parsed = parse_flag(&minimal)
+                .unwrap_or_else(|err| panic!("seed {}: {}: {}", seed, minimal, err));
+            assert_eq!(
+                render(&parsed, 40, 30),
+                render(&geo, 40, 30),
+                "seed {}: {}",
+                seed,
+                minimal
+            );
+        }
+    }
+
+    #[test]
+    fn test_to_fdl_round_trip() {
+        for seed in 0..500 {
+            let geo = random_flag(seed, 6);
+            assert_round_trips(&geo, &format!("seed {}", seed));
+            assert_round_trips(&geo.mirror_horizontal(), &format!("mirrored seed {}", seed));
+        }
+
+        for fdl in &[
+            "(h 37.5 (s #ff8000) (v 20px (s none) (s b)))",
+            "(v -20px (s w) (s r))",
+            "(rays 12 y r (s b))",
+            "(disc 50 50 30 r (tilefill v 12.5 b w))",
+            "(mask (disc 40 50 25 r (s none)) (tilefill h 10 g y))",
+            "(fimb 3 w (h 33 (s b) (h 50 (s w) (s r))))",
+        ] {
+            assert_round_trips(&flag(fdl), fdl);
+        }
+
+        assert_eq!(
+            flag("(h 50 (s r) (s w))").to_fdl().as_deref(),
+            Some("(h 50 (s r) (s w))")
+        );
+        assert_eq!(
+            FlagGeometry::Solid(Color::Rgba([0, 0, 0, 1])).to_fdl(),
+            None
+        );
+        assert_eq!(
+            FlagGeometry::procedural(|_, _| Rgb([0, 0, 0])).to_fdl(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_alpha() {
+        // A quarter opaque white panel over red
+        let geo = flag("(h 50 (s r) (alpha 0.25 (s w)))");
+        let img = render_with_background(&geo, 4, 1, Color::Red);
+        assert_eq!(img[(0, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(3, 0)], Rgb([255, 64, 64]));
+
+        let mut rgba = RgbaImage::new(4, 1);
+        rgba.clear(&Color::Red);
+        geo.draw(&mut rgba);
+        assert_eq!(rgba[(3, 0)], Rgba([255, 64, 64, 255]));
+
+        // The parts of the faded geometry don't show through one another
+        let geo = flag("(alpha 0.5 (disc 50 50 40 b (s w)))");
+        let img = render_with_background(&geo, 10, 10, Color::Black);
+        assert_eq!(img[(5, 5)], Rgb([0, 0, 128]));
+        assert_eq!(img[(0, 0)], Rgb([128, 128, 128]));
+
+        assert_eq!(flag("(alpha 1 (s r))").colors(), vec![Color::Red]);
+        assert!(parse_flag("(alpha 1.5 (s r))").is_err());
+        assert_eq!(
+            flag("(alpha 0.25 (s w))").to_fdl().as_deref(),
+            Some("(alpha 0.25 (s w))")
+        );
+    }
+
+    #[test]
+    fn test_nearest_preset() {
+        let france = flag("(h 33 (s b) (h 50 (s w) (s r)))");
+        let (name, score) = nearest_preset(&france, 40, 30).unwrap();
+        assert_eq!(name, "france");
+        assert!(score > 0.999, "{}", score);
+
+        // A slightly different shade of blue still looks most like France
+        let (name, score) =
+            nearest_preset(&flag("(h 30 (s #1010e0) (h 50 (s w) (s r)))"), 40, 30).unwrap();
+        assert_eq!(name, "france");
+        assert!(score > 0.9 && score < 1.0, "{}", score);
+
+        assert_eq!(
+            nearest_preset(&flag("(disc 50 50 25 r (s w))"), 40, 30)
+                .unwrap()
+                .0,
+            "japan"
+        );
+        for (name, fdl) in PRESETS {
+            assert!(parse_flag(fdl).is_ok(), "{}", name);
+        }
+        assert_eq!(similarity(&flag("(s s)"), &flag("(s w)"), 4, 4), 0.0);
+    }
+
+    #[test]
+    fn test_render_small() {
+        let geo = flag("(h 5 (s r) (h 5 (s w) (h 5 (s b) (h 5 (s y) (s g)))))");
+        let row = |img: &RgbImage| (0..5).map(|x| img[(x, 0)]).collect::<Vec<_>>();
+        // The parts are under half a pixel each, so most of them disappear,
+        // but the error carried along the run gives blue a pixel
+        let mut expected = vec![Color::Green.to_rgb(); 5];
+        expected[0] = Color::Blue.to_rgb();
+        assert_eq!(row(&render(&geo, 5, 1)), expected);
+
+        let expected = [
+            Color::Red,
+            Color::White,
+            Color::Blue,
+            Color::Yellow,
+            Color::Green,
+        ];
+        let expected = expected.iter().map(Color::to_rgb).collect::<Vec<_>>();
+        assert_eq!(row(&render_small(&geo, 5, 1).unwrap()), expected);
+
+        // Large enough that nothing needs to move
+        assert_eq!(render_small(&geo, 200, 2).unwrap(), render(&geo, 200, 2));
+
+        assert_eq!(geo.minimum_size(), (5, 1));
+        assert_eq!(
+            flag("(v 50 (s r) (h 50 (s w) (s b)))").minimum_size(),
+            (2, 2)
+        );
+        assert_eq!(render_small(&geo, 4, 1), Err(FlagError::TooSmall(5, 1)));
+    }
+
+    #[test]
+    fn test_quantize_to_palette() {
+        assert_eq!(Color::Rgb([250, 5, 5]).nearest_named(), Color::Red);
+        assert_eq!(Color::Rgb([20, 10, 30]).nearest_named(), Color::Black);
+        assert_eq!(Color::Rgb([240, 230, 40]).nearest_named(), Color::Yellow);
+        assert_eq!(Color::Blue.nearest_named(), Color::Blue);
+        assert_eq!(Color::None.nearest_named(), Color::None);
+
+        let geo =
+            flag("(h 50 (s #fa0505) (disc 50 50 30 #0a0af0 (tilefill v 10 #f0f0f0 #00e000)))");
+        assert_eq!(
+            geo.quantize_to_palette(),
+            flag("(h 50 (s r) (disc 50 50 30 b (tilefill v 10 w g)))")
+        );
+    }
+
+    #[test]
+    fn test_to_gimp_palette() {
+        let geo = flag("(h 33 (s b) (h 50 (s #ffffff) (v 50 (s w) (s #ff8000))))");
+        assert_eq!(
+            geo.to_gimp_palette("Flag"),
+            "GIMP Palette\nName: Flag\nColumns: 0\n#\n  \
+               0   0 255\tb\n\
+             255 255 255\t#ffffff\n\
+             255 128   0\t#ff8000\n"
+        );
+
+        let palette = flag("(disc 50 50 30 none (s r))").to_gimp_palette("Red");
+        assert_eq!(palette.lines().count(), 5);
+        assert!(palette.ends_with("255   0   0\tr\n"));
+    }
+
+    #[test]
+    fn test_grid() {
+        // Red on the left, blue along the bottom, crossing in the corner
+        let geo = flag("(grid (h 50 (s r) (s none)) (v 50 (s none) (s b)) multiply)");
+        let img = render_with_background(&geo, 4, 4, Color::White);
+        assert_eq!(img[(0, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(3, 0)], Color::White.to_rgb());
+        assert_eq!(img[(0, 3)], Color::Black.to_rgb());
+        assert_eq!(img[(3, 3)], Color::Blue.to_rgb());
+
+        // Crossing stripes repeated over the whole flag
+        let geo = flag("(grid (tilefill h 25 r w) (tilefill v 25 b w) mix)");
+        let img = render(&geo, 4, 4);
+        assert_eq!(img[(0, 0)], Rgb([128, 0, 128]));
+        assert_eq!(img[(1, 0)], Rgb([128, 128, 255]));
+        assert_eq!(img[(0, 1)], Rgb([255, 128, 128]));
+        assert_eq!(img[(1, 1)], Color::White.to_rgb());
+        assert_eq!(img[(2, 2)], Rgb([128, 0, 128]));
+
+        assert_eq!(geo.colors().len(), 3);
+        assert!(parse_flag("(grid (s r) (s b) screen)").is_err());
+        assert_eq!(
+            geo.to_fdl().as_deref(),
+            Some("(grid (tilefill h 25 r w) (tilefill v 25 b w) mix)")
+        );
+    }
+
+    #[test]
+    fn test_mask() {
+        // Horizontal stripes showing only within a disc
+        let geo = flag("(mask (disc 50 50 40 r (s none)) (tilefill v 10 b y))");
+        let img = render(&geo, 20, 20);
+        assert_eq!(img[(10, 10)], Color::Yellow.to_rgb());
+        assert_eq!(img[(10, 13)], Color::Blue.to_rgb());
+        assert_eq!(img[(0, 0)], Color::Black.to_rgb());
+        assert_eq!(img[(19, 10)], Color::Black.to_rgb());
+        assert_eq!(geo.colors(), vec![Color::Blue, Color::Yellow]);
+
+        let img = render(
+            &flag("(v 50 (s w) (mask (h 50 (s r) (s none)) (s g)))"),
+            4,
+            4,
+        );
+        assert_eq!(img[(0, 3)], Color::Green.to_rgb());
+        assert_eq!(img[(3, 3)], Color::Black.to_rgb());
+
+        let sexpr = SExpr::parse(&mut "(mask (s r))".chars().peekable()).unwrap();
+        assert!(sexpr.to_flag_geometry().is_err());
+    }
+
+    #[test]
+    fn test_square_ratio() {
+        assert_eq!(ratio_dimensions("1:1"), Some((300, 300)));
+        assert_eq!(ratio_dimensions("3:2"), Some((450, 300)));
+        assert_eq!(ratio_dimensions("0:1"), None);
+        assert_eq!(ratio_dimensions("square"), None);
+
+        // A white cross whose arms are a fifth of the side, centered
+        let (width, height) = ratio_dimensions("1:1").unwrap();
+        let img = render(
+            &flag(
+                "(v 40 (t row (h 40 (s r) (h 33.333 (s w) (s r))))
+                    (v 33.333 (s w) (r row)))",
+            ),
+            width,
+            height,
+        );
+
+        let white = Color::White.to_rgb();
+        let (cx, cy) = (width / 2, height / 2);
+        let left = (0..cx).rev().take_while(|&x| img[(x, cy)] == white).count();
+        let right = (cx..width).take_while(|&x| img[(x, cy)] == white).count();
+        let up = (0..cy).rev().take_while(|&y| img[(cx, y)] == white).count();
+        let down = (cy..height).take_while(|&y| img[(cx, y)] == white).count();
+        assert_eq!(left, cx as usize);
+        assert_eq!((left, right, up, down), (left, left, left, left));
+
+        // The arms are equally thick in both directions
+        assert_eq!(img[(119, 150)], Color::White.to_rgb());
+        assert_eq!(img[(119, 119)], Color::Red.to_rgb());
+        assert_eq!(img[(180, 180)], Color::Red.to_rgb());
+        assert_eq!(img[(120, 120)], white);
+        assert_eq!(img[(179, 179)], white);
+    }
+
+    #[test]
+    fn test_validate_command() {
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(run(&args(&["validate", "(h 50 (s r) (s w))"])), 0);
+        assert_ne!(run(&args(&["validate", "(h 50 (s r) (s q))"])), 0);
+        assert_ne!(run(&args(&["frobnicate"])), 0);
+
+        // The cause is reported along with the exit code
+        for fdl in &["(h 50 (s r) (s q))", "(q r)", "(h 50 (s r)"] {
+            assert_eq!(run(&args(&["validate", fdl])), EXIT_INVALID_FLAG);
+            assert_eq!(
+                run(&args(&["svg", fdl, "/nonexistent/out.svg"])),
+                EXIT_INVALID_FLAG
+            );
+        }
+        let message = |fdl| {
+            let mut out = Vec::new();
+            let code = report_invalid_flag(&parse_flag(fdl).unwrap_err(), &mut out);
+            (code, String::from_utf8(out).unwrap())
+        };
+        assert_eq!(
+            message("(h 50 (s r) (s q))"),
+            (
+                EXIT_INVALID_FLAG,
+                "invalid flag: unknown color q\n".to_string()
+            )
+        );
+        assert_eq!(
+            message("(q r)"),
+            (
+                EXIT_INVALID_FLAG,
+                "invalid flag: unknown operator q\n".to_string()
+            )
+        );
+        assert_eq!(
+            message("(h 50 (r nowhere) (s w))"),
+            (
+                EXIT_INVALID_FLAG,
+                "invalid flag: no tag named nowhere\n".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_flags_equal() {
+        let tagged = flag("(h 50 (t half (s r)) (r half))");
+        let plain = flag("(s r)");
+        assert!(flags_equal(&tagged, &plain, 40, 30));
+
+        let split = flag("(h 50 (s r) (s w))");
+        assert!(!flags_equal(&split, &plain, 40, 30));
+        assert_eq!(pixel_difference(&split, &plain, 40, 30), 20 * 30);
+    }
+
+    #[test]
+    fn test_flags_approx_equal() {
+        let a = flag("(h 50 (s r) (s #808080))");
+        let b = flag("(h 50 (s r) (s #808280))");
+        assert!(flags_approx_equal(&a, &b, 4, 2, 2));
+        assert!(!flags_approx_equal(&a, &b, 4, 2, 1));
+        assert!(!flags_equal(&a, &b, 4, 2));
+        assert!(flags_approx_equal(&b, &a, 4, 2, 2));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let geo = flag("(h 50 (s r) (h 50 (s w) (s b)))");
+        assert_eq!(geo.depth(), 3);
+        assert_eq!(geo.element_count(), 5);
+
+        let geo = flag("(disc 50 50 30 r (s w))");
+        assert_eq!(geo.depth(), 2);
+        assert_eq!(geo.element_count(), 2);
+    }
+
+    #[test]
+    fn test_weight_parsing() {
+        assert_eq!(parse_weight("1.5"), Some(1.5));
+        assert_eq!(parse_weight("50%"), Some(50.0));
+        for bad in &["1,000", "1,5", "1e2", "inf", "NaN", "-5", "", "%"] {
+            assert_eq!(parse_weight(bad), None, "{}", bad);
+        }
+
+        assert!(parse_flag("(h 1.5 (s r) (s w))").is_ok());
+        assert!(parse_flag("(h 1,000 (s r) (s w))").is_err());
+    }
+
+    #[test]
+    fn test_render_antialiased() {
+        let geo = flag("(disc 50 50 40 s (s w))");
+        let is_intermediate =
+            |p: &Rgb<u8>| *p != Color::Black.to_rgb() && *p != Color::White.to_rgb();
+
+        assert!(!render(&geo, 20, 20).pixels().any(is_intermediate));
+        assert!(render_antialiased(&geo, 20, 20, 4)
+            .pixels()
+            .any(is_intermediate));
+        assert_eq!(render_antialiased(&geo, 20, 20, 1), render(&geo, 20, 20));
+    }
+
+    #[test]
+    fn test_gamma_correct_blending() {
+        let (black, white) = (Color::Black.to_rgb(), Color::White.to_rgb());
+        assert_eq!(Blending::Srgb.mix(black, white, 0.5), Rgb([128, 128, 128]));
+        assert_eq!(
+            Blending::Linear.mix(black, white, 0.5),
+            Rgb([188, 188, 188])
+        );
+        assert_eq!(Blending::Linear.mix(black, white, 0.0), black);
+        assert_eq!(Blending::Linear.mix(black, white, 1.0), white);
+
+        for channel in 0..=255 {
+            assert_eq!(linear_to_srgb(srgb_to_linear(channel)), channel);
+        }
+
+        let geo = flag("(h 50 (s s) (s w))");
+        let srgb = render_antialiased_with(&geo, 1, 1, 2, Blending::Srgb);
+        let linear = render_antialiased_with(&geo, 1, 1, 2, Blending::Linear);
+        assert_eq!(srgb[(0, 0)], Rgb([128, 128, 128]));
+        assert_eq!(linear[(0, 0)], Rgb([188, 188, 188]));
+    }
+
+    #[test]
+    fn test_save_image() {
+        let img = render(&flag("(h 50 (s r) (s w))"), 40, 30);
+
+        let path = std::env::temp_dir().join("flagrant-test-save-image.tiff");
+        let path = path.to_str().unwrap();
+        save_image(&img, path, "(h 50 (s r) (s w))").unwrap();
+        {
+            use image::GenericImageView;
+            assert_eq!(image::open(path).unwrap().dimensions(), (40, 30));
+        }
+        std::fs::remove_file(path).unwrap();
+
+        assert!(save_image(&img, "out.webp", "")
+            .unwrap_err()
+            .contains(".webp"));
+        assert!(save_image(&img, "out.xyz", "")
+            .unwrap_err()
+            .contains(".xyz"));
+    }
+
+    #[test]
+    fn test_fdl_from_png() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let fdl = "(h 33 (s w) (v 50 (s r) (s g)))";
+        let path = std::env::temp_dir().join("flagrant-test-fdl-from-png.png");
+        let path = path.to_str().unwrap();
+        let args = ["render".to_string(), fdl.to_string(), path.to_string()];
+        assert_eq!(run(&args), 0);
+        assert_eq!(fdl_from_png(path).as_deref(), Some(fdl));
+        {
+            use image::GenericImageView;
+            assert_eq!(image::open(path).unwrap().dimensions(), (400, 300));
+        }
+        std::fs::remove_file(path).unwrap();
+
+        let png = render_png(&flag("(s r)"), 2, 2).unwrap();
+        assert_eq!(png_text(&png, FDL_KEYWORD), None);
+        assert_eq!(png_with_text(&png, FDL_KEYWORD, "(t \u{2603} (s r))"), None);
+
+        // Truncated or padded files are rejected rather than panicking
+        let png = png_with_text(&png, FDL_KEYWORD, fdl).unwrap();
+        assert_eq!(png_text(&png, FDL_KEYWORD).as_deref(), Some(fdl));
+        for extra in 1..4 {
+            let mut padded = png.clone();
+            padded.resize(png.len() + extra, 0);
+            assert_eq!(png_chunks(&padded), None);
+        }
+        for len in PNG_SIGNATURE.len()..png.len() {
+            png_text(&png[..len], FDL_KEYWORD);
+        }
+        // The text chunk follows the 25 bytes of the header chunk
+        let text_start = PNG_SIGNATURE.len() + 25;
+        let text_end = text_start + 12 + FDL_KEYWORD.len() + 1 + fdl.len();
+        for len in text_start + 1..text_end {
+            assert_eq!(png_text(&png[..len], FDL_KEYWORD), None, "{} bytes", len);
+        }
+        assert!(png_text(&png[..text_end], FDL_KEYWORD).is_some());
+        let mut huge = PNG_SIGNATURE.to_vec();
+        huge.extend_from_slice(&[0xff; 12]);
+        assert_eq!(png_chunks(&huge), None);
+    }
+
+    #[test]
+    fn test_tilefill() {
+        // Stripes of 9.5 pixels, alternately rounded up and down
+        let img = render(&flag("(tilefill h 10% r w)"), 95, 2);
+        for x in 0..95 {
+            let stripe = (0..).find(|&i| ((i + 1) as f32 * 9.5).round() as u32 > x);
+            let expected = if stripe.unwrap() % 2 == 0 {
+                Color::Red
+            } else {
+                Color::White
+            };
+            assert_eq!(img[(x, 1)], expected.to_rgb(), "column {}", x);
+        }
+
+        let img = render(&flag("(tilefill v 25 b y)"), 1, 8);
+        assert_eq!(img[(0, 1)], Color::Blue.to_rgb());
+        assert_eq!(img[(0, 2)], Color::Yellow.to_rgb());
+        assert_eq!(img[(0, 7)], Color::Yellow.to_rgb());
+    }
+
+    #[test]
+    fn test_remainder_weight() {
+        for &width in &[100, 40] {
+            let leaves = flag("(h 20px (s s) * (s w))").leaves(width, 10);
+            assert_eq!(
+                leaves,
+                vec![
+                    (Rect::new(0, 0, 20, 10), Color::Black),
+                    (Rect::new(20, 0, width - 20, 10), Color::White),
+                ]
+            );
+        }
+
+        assert_eq!(
+            flag("(h * (s s) 20px (s w))"),
+            flag("(h -20px (s s) (s w))")
+        );
+        assert_eq!(flag("(v * (s r) 30 (s w))"), flag("(v 70 (s r) (s w))"));
+        assert_eq!(flag("(v * (s r) * (s w))"), flag("(v 50 (s r) (s w))"));
+        assert!(parse_flag("(v * (s r) 3q (s w))").is_err());
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let exprs = SExpr::parse_all("(s r) (s w)".chars()).collect::<Vec<_>>();
+        assert_eq!(exprs.len(), 2);
+        assert_eq!(
+            exprs[1].to_flag_geometry(),
+            Ok(UnresolvedFlagGeometry::Solid(Color::White))
+        );
+
+        let exprs = SExpr::parse_all("\n  (h 50 (s r)\n (s w))\n\n(s b)\n".chars());
+        assert_eq!(exprs.count(), 2);
+        assert_eq!(SExpr::parse_all("   ".chars()).count(), 0);
+        assert_eq!(SExpr::parse_all("(s r) ) (s w)".chars()).count(), 1);
+    }
+
+    #[test]
+    fn test_to_pretty_string() {
+        let fdl = "(v 40 (t top_strip (h 40 (h 65 (s b) (s y)) (s b))) (v 30 (s y) (r top_strip)))";
+        let sexpr = SExpr::parse(&mut fdl.chars().peekable()).unwrap();
+        let pretty = sexpr.to_pretty_string(4);
+        assert_eq!(
+            pretty,
+            "(v 40
+    (t top_strip
+        (h 40
+            (h 65 (s b) (s y))
+            (s b)
+        )
+    )
+    (v 30 (s y) (r top_strip))
+)"
+        );
+        assert_eq!(SExpr::parse(&mut pretty.chars().peekable()), Some(sexpr));
+
+        for fdl in &[
+            "(s r)",
+            "(h 1 (s r) 1 (s w))",
+            "(t \"top stripe\" (h 50 (s b) (v 50 (s w) (s r))))",
+        ] {
+            let sexpr = SExpr::parse(&mut fdl.chars().peekable()).unwrap();
+            let pretty = sexpr.to_pretty_string(2);
+            assert_eq!(
+                SExpr::parse(&mut pretty.chars().peekable()),
+                Some(sexpr),
+                "{}",
+                pretty
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_bom_crlf() {
+        let clean = "(h 50 (s r) (s w))";
+        let messy = "\u{feff}(h 50 (s r)\r\n (s w))\r\n";
+        assert_eq!(parse_flag(messy), parse_flag(clean));
+        assert!(parse_flag(messy).is_ok());
+        assert_eq!(
+            SExpr::parse_all(messy.chars()).collect::<Vec<_>>(),
+            SExpr::parse_all(clean.chars()).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            SExpr::parse_with_spans(messy).map(|(sexpr, _)| sexpr),
+            SExpr::parse_with_spans(clean).map(|(sexpr, _)| sexpr)
+        );
+    }
+
+    #[test]
+    fn test_parse_with_spans() {
+        let fdl = "(h 1 (s r) 1 (s w))";
+        let (sexpr, spans) = SExpr::parse_with_spans(fdl).unwrap();
+        assert_eq!(
+            Some(&sexpr),
+            SExpr::parse(&mut fdl.chars().peekable()).as_ref()
+        );
+        assert_eq!(spans.span, 0..fdl.len());
+        assert_eq!(&fdl[spans.children[4].span.clone()], "(s w)");
+        assert_eq!(&fdl[spans.children[4].children[1].span.clone()], "w");
+
+        let fdl = " (v 20px (t \"top bar\" (s b)) * (disc 50 50 30 r (s w)))";
+        let (sexpr, spans) = SExpr::parse_with_spans(fdl).unwrap();
+        let (geo, map) = sexpr.to_flag_geometry_spanned(&spans).unwrap();
+        let source = |geo: &UnresolvedFlagGeometry| &fdl[map.span(geo).unwrap()];
+        assert_eq!(source(&geo), fdl.trim());
+        match &*geo {
+            UnresolvedFlagGeometry::Vertical(car, cdr, _) => {
+                assert_eq!(source(car), "(t \"top bar\" (s b))");
+                assert_eq!(source(cdr), "(disc 50 50 30 r (s w))");
+                match &**car {
+                    UnresolvedFlagGeometry::Tag(_, geo) => assert_eq!(source(geo), "(s b)"),
+                    _ => panic!("expected a tag"),
+                }
+            }
+            _ => panic!("expected a split"),
+        }
+        assert_eq!(map.span(&UnresolvedFlagGeometry::Solid(Color::Blue)), None);
+
+        assert!(SExpr::parse_with_spans("(s \"w)").is_none());
+        assert!(SExpr::parse_with_spans(")").is_none());
+    }
+
+    #[test]
+    fn test_malformed_split() {
+        for fdl in &[
+            "(h 1 (s r) 1)",
+            "(v 50 (s r))",
+            "(h 20px (s r) 10px (s w))",
+            "(h 0 (s r) 0% (s w))",
+        ] {
+            let sexpr = SExpr::parse(&mut fdl.chars().peekable()).unwrap();
+            assert_eq!(
+                sexpr.to_flag_geometry().unwrap_err(),
+                FlagError::MalformedSplit(fdl.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_color_bounds() {
+        let geo = flag("(v 25 (s r) (v 50 (s w) (s r)))");
+        assert_eq!(
+            geo.color_bounds(Color::White, 40, 40),
+            Some(Rect::new(0, 10, 40, 15))
+        );
+        assert_eq!(
+            geo.color_bounds(Color::Red, 40, 40),
+            Some(Rect::new(0, 0, 40, 40))
+        );
+        assert_eq!(geo.color_bounds(Color::Blue, 40, 40), None);
+    }
+
+    #[test]
+    fn test_tag_regions() {
+        let fdl = "(h 50 (t stripe (s r)) (v 50 (s w) (r stripe)))";
+        let ufg = SExpr::parse(&mut fdl.chars().peekable())
+            .unwrap()
+            .to_flag_geometry()
+            .unwrap();
+        assert_eq!(
+            ufg.tag_regions("stripe", 40, 20),
+            vec![Rect::new(0, 0, 20, 20), Rect::new(20, 10, 20, 10)]
+        );
+    }
+
+    #[test]
+    fn test_expected_list() {
+        let sexpr = SExpr::parse(&mut "r".chars().peekable()).unwrap();
+        assert_eq!(
+            sexpr.to_flag_geometry().unwrap_err(),
+            FlagError::ExpectedList("r".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pixel_weights() {
+        let geo = flag("(h 20px (s s) (s w))");
+        for &width in &[100, 300] {
+            let img = render(&geo, width, 10);
+            assert_eq!(img[(19, 5)], Color::Black.to_rgb());
+            assert_eq!(img[(20, 5)], Color::White.to_rgb());
+            assert_eq!(img[(width - 1, 5)], Color::White.to_rgb());
+        }
+
+        // Mirroring moves the fixed stripe to the other side
+        let img = render(&geo.mirror_horizontal(), 100, 10);
+        assert_eq!(img[(79, 5)], Color::White.to_rgb());
+        assert_eq!(img[(80, 5)], Color::Black.to_rgb());
+        assert_eq!(geo.mirror_horizontal(), flag("(h -20px (s w) (s s))"));
+
+        // A fixed size never exceeds the area
+        let img = render(&geo, 10, 10);
+        assert_eq!(img[(9, 5)], Color::Black.to_rgb());
+        assert!(parse_flag("(h 2.5px (s s) (s w))").is_err());
+
+        // With a weight for each part, a percentage next to a size in pixels
+        // takes whatever is left
+        let geo = flag("(h 20px (s s) 1 (s w))");
+        assert_eq!(geo, flag("(h 20px (s s) (s w))"));
+        for &width in &[100, 300] {
+            let img = render(&geo, width, 10);
+            assert_eq!(img[(19, 5)], Color::Black.to_rgb());
+            assert_eq!(img[(20, 5)], Color::White.to_rgb());
+            assert_eq!(img[(width - 1, 5)], Color::White.to_rgb());
+        }
+        assert_eq!(
+            flag("(v 40% (s w) 8px (s s))"),
+            flag("(v -8px (s w) (s s))")
+        );
+    }
+
+    #[test]
+    fn test_render_into() {
+        let mut sheet = RgbImage::new(20, 10);
+        render_into(&flag("(s r)"), &mut sheet, 0, 0, 10, 10);
+        render_into(&flag("(v 50 (s w) (s b))"), &mut sheet, 10, 0, 10, 10);
+
+        assert_eq!(sheet[(9, 0)], Color::Red.to_rgb());
+        assert_eq!(sheet[(9, 9)], Color::Red.to_rgb());
+        assert_eq!(sheet[(10, 4)], Color::White.to_rgb());
+        assert_eq!(sheet[(10, 5)], Color::Blue.to_rgb());
+        assert_eq!(sheet[(19, 9)], Color::Blue.to_rgb());
+
+        // Flags hanging over the edge are clipped rather than panicking
+        render_into(&flag("(s y)"), &mut sheet, 15, 5, 10, 10);
+        assert_eq!(sheet[(14, 9)], Color::Blue.to_rgb());
+        assert_eq!(sheet[(15, 5)], Color::Yellow.to_rgb());
+        assert_eq!(sheet[(19, 9)], Color::Yellow.to_rgb());
+    }
+
+    #[test]
+    fn test_unicode_whitespace() {
+        let expected = flag("(h 50 (s r) (s w))");
+        for fdl in &[
+            "(h\t50\t(s\tr)\t(s w))",
+            "(h\n50\n(s r)\n(s w)\n)",
+            "(h\u{a0}50\u{a0}(s\u{a0}r)\u{a0}(s\u{a0}w))",
+            "(\u{2003}h 50 (s r\u{3000}) (s w)\u{a0})",
+            "( h 50 ( s r ) ( s w ) )",
+        ] {
+            assert_eq!(parse_flag(fdl), Ok(expected.clone()), "{:?}", fdl);
+        }
+
+        let sweden = "
+            (v 40
+            \t(t top_strip
+            \t\t(h 40 (h 65 (s b) (s y)) (s b))
+            \t)
+            \t(v 30\r\n(s y)\r\n(r top_strip))
+            )
+        ";
+        assert!(parse_flag(sweden).is_ok());
+
+        assert!(SExpr::parse(&mut "( )".chars().peekable())
+            .unwrap()
+            .list()
+            .unwrap()
+            .is_empty());
+        assert!(SExpr::parse(&mut "\u{a0})".chars().peekable()).is_none());
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut img = RgbImage::new(3, 2);
+        img.clear(&Color::Yellow);
+        assert!(img.pixels().all(|p| *p == Color::Yellow.to_rgb()));
+
+        img.clear(&Color::None);
+        assert!(img.pixels().all(|p| *p == Color::Yellow.to_rgb()));
+
+        // A translucent color is blended like any other rectangle
+        let mut expected = img.clone();
+        expected.rectangle(0, 0, 3, 2, &Color::Rgba([0, 0, 255, 128]));
+        img.clear(&Color::Rgba([0, 0, 255, 128]));
+        assert_eq!(img, expected);
+        assert_ne!(img[(0, 0)], Rgb([0, 0, 255]));
+
+        let mut canvas = RecordingCanvas::new(3, 2);
+        canvas.clear(&Color::Red);
+        assert_eq!(canvas.rectangles, vec![(Rect::new(0, 0, 3, 2), Color::Red)]);
+    }
+
+    #[test]
+    fn test_degenerate_weights() {
+        let white = render(&flag("(s w)"), 10, 10);
+        for fdl in &[
+            "(h 0 (s r) (s w))",
+            "(v 0% (s r) (s w))",
+            "(h 0px (s r) (s w))",
+            "(v -0px (s w) (s r))",
+            "(h 100 (s w) (s r))",
+            "(h 150 (s w) (s r))",
+            "(v 50px (s w) (s r))",
+            "(disc 50 50 0 r (s w))",
+        ] {
+            assert_eq!(render(&flag(fdl), 10, 10), white, "{}", fdl);
+        }
+
+        // Zero sized stripes and rays still produce something defined
+        let img = render(&flag("(tilefill h 0 r w)"), 4, 1);
+        assert_eq!(img[(0, 0)], Color::Red.to_rgb());
+        assert_eq!(img[(1, 0)], Color::White.to_rgb());
+        render(&flag("(rays 0 r y (s w))"), 10, 10);
+        render(&flag("(h 50 (s r) (s w))"), 0, 0);
+
+        assert!(parse_flag("(h -10 (s r) (s w))").is_err());
+    }
+
+    #[test]
+    fn test_palette() {
+        let fdl = "(h 50 (s primary) (s accent))";
+        let palette = |primary, accent| {
+            let mut palette = HashMap::new();
+            palette.insert("primary".to_string(), primary);
+            palette.insert("accent".to_string(), accent);
+            palette
+        };
+
+        let day = parse_flag_with_palette(fdl, &palette(Color::Blue, Color::Yellow)).unwrap();
+        let night =
+            parse_flag_with_palette(fdl, &palette(Color::Black, Color::Rgb([0, 0, 80]))).unwrap();
+        assert_eq!(day, flag("(h 50 (s b) (s y))"));
+        assert_ne!(render(&day, 4, 2), render(&night, 4, 2));
+        assert_eq!(render(&night, 4, 2)[(3, 1)], Rgb([0, 0, 80]));
+
+        // Built in colors take precedence, and unknown names still fail
+        let red = parse_flag_with_palette("(s r)", &palette(Color::Blue, Color::Blue));
+        assert_eq!(red, Ok(flag("(s r)")));
+        assert!(parse_flag(fdl).is_err());
+    }
+
+    #[test]
+    fn test_draw_dyn() {
+        let geo = flag("(h 50 (s r) (s w))");
+
+        let mut img = RgbImage::new(4, 2);
+        let backend: &mut dyn MsPaint = &mut img;
+        geo.draw_dyn(backend);
+        assert_eq!(img, render(&geo, 4, 2));
+
+        let mut backends: Vec<Box<dyn MsPaint>> = vec![
+            Box::new(RgbImage::new(4, 2)),
+            Box::new(PpmCanvas::new(4, 2)),
+            Box::new(SvgCanvas::new(4, 2)),
+        ];
+        for backend in backends.iter_mut() {
+            geo.draw_dyn(backend.as_mut());
+            assert_eq!((backend.width(), backend.height()), (4, 2));
+        }
+    }
+
+    #[test]
+    fn test_render_png_with_dpi() {
+        let geo = flag("(v 50 (s b) (s y))");
+        let png = render_png_with_dpi(&geo, 40, 30, 300.0).unwrap();
+
+        let chunks = png_chunks(&png).unwrap();
+        let phys = chunks.iter().find(|(kind, _)| *kind == b"pHYs").unwrap().1;
+        assert_eq!(phys, &[0, 0, 0x2e, 0x23, 0, 0, 0x2e, 0x23, 1]);
+        assert_eq!(chunks[0].0, b"IHDR");
+
+        // The image itself is the same as without a resolution
+        let plain = render_png(&geo, 40, 30).unwrap();
+        let idat = |chunks: &[(&[u8], &[u8])]| {
+            chunks
+                .iter()
+                .filter(|(kind, _)| *kind == b"IDAT")
+                .flat_map(|(_, data)| data.to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(idat(&chunks), idat(&png_chunks(&plain).unwrap()));
+    }
+
+    #[test]
+    fn test_parse_iter() {
+        for fdl in &[
+            "(h 33 (s b) (h 50 (s w) (s r)))",
+            "  (t \"top stripe\" (s b))  ",
+            "(s\u{a0}r\t)",
+            "( )",
+            "((s r",
+            "r",
+            "\"ab\"cd",
+            "a(b",
+            "",
+            ")",
+            "(t \"open (s b))",
+        ] {
+            let mut recursive = fdl.chars().peekable();
+            let mut iterative = fdl.chars().peekable();
+            assert_eq!(
+                SExpr::parse_iter(&mut iterative),
+                SExpr::parse(&mut recursive),
+                "{:?}",
+                fdl
+            );
+            assert_eq!(iterative.collect::<String>(), recursive.collect::<String>());
+        }
+
+        let depth = 100_000;
+        let fdl = "(".repeat(depth) + "s r" + &")".repeat(depth);
+        let mut sexpr = SExpr::parse_iter(&mut fdl.chars().peekable()).unwrap();
+
+        // Take the result apart one level at a time, as dropping it whole
+        // would recurse just as deeply
+        let mut levels = 0;
+        while let SExpr::List(mut list) = sexpr {
+            levels += 1;
+            sexpr = match list.pop() {
+                Some(inner @ SExpr::List(_)) => inner,
+                _ => break,
+            };
+        }
+        assert_eq!(levels, depth);
+    }
+
+    #[test]
+    fn test_slice() {
+        let geo = flag("(h 50 (s r) (s w))");
+        assert_eq!(geo.slice(0.0, 0.0, 50.0, 100.0), Some(flag("(s r)")));
+        assert_eq!(geo.slice(50.0, 0.0, 50.0, 100.0), Some(flag("(s w)")));
+        assert_eq!(geo.slice(25.0, 0.0, 50.0, 100.0), Some(geo.clone()));
+
+        let france = flag("(h 25 (s b) (h 50 (s w) (s r)))");
+        assert_eq!(
+            france.slice(0.0, 0.0, 50.0, 100.0),
+            Some(flag("(h 50 (s b) (s w))"))
+        );
+
+        let madagascar = flag("(h 25 (s w) (v 50 (s r) (s g)))");
+        assert_eq!(
+            madagascar.slice(0.0, 50.0, 100.0, 50.0),
+            Some(flag("(h 25 (s w) (s g))"))
+        );
+
+        // Discs can only be kept whole
+        let japan = flag("(disc 50 50 30 r (s w))");
+        assert_eq!(japan.slice(0.0, 0.0, 100.0, 100.0), Some(japan.clone()));
+        assert_eq!(japan.slice(0.0, 0.0, 50.0, 100.0), None);
+        assert_eq!(
+            flag("(h 50 (s b) (disc 50 50 30 r (s w)))").slice(0.0, 0.0, 50.0, 100.0),
+            Some(flag("(s b)"))
+        );
+    }
+
+    #[test]
+    fn test_tint() {
+        assert_eq!(
+            flag("(tint r (s w))"),
+            FlagGeometry::Solid(Color::Rgb([255, 0, 0]))
+        );
+        assert_eq!(
+            render(&flag("(tint r (s w))"), 2, 2),
+            render(&flag("(s r)"), 2, 2)
+        );
+
+        let img = render(
+            &flag("(h 50 (tint #808080 (t stripe (s w))) (r stripe))"),
+            2,
+            1,
+        );
+        assert_eq!(img[(0, 0)], Rgb([128, 128, 128]));
+        assert_eq!(img[(1, 0)], Color::White.to_rgb());
+
+        assert_eq!(Color::None.multiply(&Color::Red), Color::None);
+        assert_eq!(
+            Color::Rgba([255, 255, 255, 100]).multiply(&Color::Blue),
+            Color::Rgba([0, 0, 255, 100])
+        );
+    }
+
+    #[test]
+    fn test_render_batch() {
+        let prefix = std::env::temp_dir().join("flagrant-test-render-batch");
+        let prefix = prefix.to_str().unwrap();
+        let path = |i| format!("{}-{}.png", prefix, i);
+
+        let args = [
+            "render",
+            "--out-prefix",
+            prefix,
+            "(h 50 (s r) (s w))",
+            "(s q)",
+            "(v 50 (s b) (s y))",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(run(&args), EXIT_INVALID_FLAG);
+
+        assert!(std::path::Path::new(&path(0)).exists());
+        assert!(!std::path::Path::new(&path(1)).exists());
+        assert!(std::path::Path::new(&path(2)).exists());
+        std::fs::remove_file(path(0)).unwrap();
+        std::fs::remove_file(path(2)).unwrap();
+
+        assert_eq!(
+            run(&["render".to_string(), "--out-prefix".to_string()]),
+            EXIT_USAGE
+        );
+    }
+
+    #[test]
+    fn test_read_fdl() {
+        /// Hands out a few bytes at a time, being interrupted every so often,
+        /// the way a pipe might.
+        struct Trickle<'a> {
+            data: &'a [u8],
+            reads: usize,
+        }
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.reads += 1;
+                if self.reads.is_multiple_of(3) {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                let n = self.data.len().min(buf.len()).min(2);
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data = &self.data[n..];
+                Ok(n)
+            }
+        }
+
+        let fdl = "(h 33 (s b)\n    (h 50 (s w) (s r)))\n";
+        let read = read_fdl(Trickle {
+            data: fdl.as_bytes(),
+            reads: 0,
+        })
+        .unwrap();
+        assert_eq!(read, fdl);
+        assert_eq!(parse_flag(&read), parse_flag(fdl));
+
+        let invalid = Trickle {
+            data: &[b'(', 0xff, b')'],
+            reads: 0,
+        };
+        assert!(read_fdl(invalid).is_err());
+    }
+
+    #[test]
+    fn test_stdin_argument() {
+        assert_eq!(stdin_argument(&["render", "-"]), Some(1));
+        assert_eq!(stdin_argument(&["render", "-", "out.png"]), Some(1));
+        assert_eq!(stdin_argument(&["validate", "-"]), Some(1));
+        assert_eq!(stdin_argument(&["svg", "-", "out.svg"]), Some(1));
+        assert_eq!(stdin_argument(&["-"]), Some(0));
+
+        // Outputs and the rest of a batch are taken literally
+        assert_eq!(stdin_argument(&["render", "(s r)", "-"]), None);
+        assert_eq!(stdin_argument(&["render", "(s r)", "(s w)", "-"]), None);
+        assert_eq!(stdin_argument(&["colors"]), None);
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let colors = flag("(h 50 (s r) (v 50 (s w) (s #ff8000)))").colors();
+        assert_eq!(
+            expand_template("{index}-{colors}.png", 3, &colors[..2]),
+            Ok("3-r-w.png".to_string())
+        );
+        assert_eq!(
+            expand_template("flags/{colors}.png", 0, &colors),
+            Ok("flags/r-w-ff8000.png".to_string())
+        );
+        assert_eq!(
+            expand_template("{index}-{name}.png", 0, &colors),
+            Err("{name}".to_string())
+        );
+        assert_eq!(
+            expand_template("{index", 0, &colors),
+            Err("{index".to_string())
+        );
+
+        let args = ["render", "--out-template", "{size}.png", "(s r)", "(s w)"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(run(&args), EXIT_USAGE);
+    }
+
+    #[test]
+    fn test_reverse_side() {
+        let ufg = |fdl: &str| {
+            SExpr::parse(&mut fdl.chars().peekable())
+                .unwrap()
+                .to_flag_geometry()
+                .unwrap()
+        };
+
+        // A motto in the hoist, a charge set off towards it and the motto
+        // repeated in the fly
+        let geo =
+            ufg("(h 40 (t motto (h 30 (s r) (s w))) (h 20 (disc 30 50 20 y (s b)) (r motto)))");
+        assert_eq!(
+            geo.reverse_side(&[]).resolve_all(),
+            Ok(flag(
+                "(h 60 (h 80 (h 70 (s w) (s r)) (disc 70 50 20 y (s b))) (h 70 (s w) (s r)))"
+            ))
+        );
+        assert_eq!(
+            geo.reverse_side(&["motto"]).resolve_all(),
+            Ok(flag(
+                "(h 60 (h 80 (h 30 (s r) (s w)) (disc 70 50 20 y (s b))) (h 30 (s r) (s w)))"
+            ))
+        );
+        assert_eq!(
+            geo.reverse_side(&[]).resolve_all().unwrap(),
+            geo.resolve_all().unwrap().mirror_horizontal()
+        );
+
+        // Side by side stripes start from the other end, while stacked ones
+        // are the same from the back
+        for fdl in &[
+            "(tilefill h 25 r w)",
+            "(h 40 (tilefill v 30 r w) (tilefill h 15 b y))",
+        ] {
+            let geo = ufg(fdl);
+            assert_eq!(
+                geo.reverse_side(&[]).resolve_all(),
+                Ok(geo.resolve_all().unwrap().mirror_horizontal())
+            );
+        }
+        let img = render(
+            &ufg("(tilefill h 25 r w)")
+                .reverse_side(&[])
+                .resolve_all()
+                .unwrap(),
+            10,
+            1,
+        );
+        assert_eq!(img[(0, 0)], Color::White.to_rgb());
+        assert_eq!(img[(9, 0)], Color::Red.to_rgb());
+
+        // The charge is drawn mirrored, over a field reversed like the rest
+        let flipped = |geo| UnresolvedFlagGeometry::Flipped(Direction::Horizontal, Rc::new(geo));
+        assert_eq!(
+            ufg("(emblem \"charge.png\" 50 (h 30 (s r) (s w)))").reverse_side(&[]),
+            flipped(UnresolvedFlagGeometry::Emblem(
+                "charge.png".to_string(),
+                50.0,
+                None,
+                Rc::new(flipped(ufg("(h 70 (s w) (s r))"))),
+            ))
+        );
+
+        let path = std::env::temp_dir().join("flagrant-test-reverse-include.fdl");
+        std::fs::write(&path, "(h 30 (s r) (tilefill h 20 b w))").unwrap();
+        let geo = ufg(&format!(
+            "(v 50 (include {:?}) (s y))",
+            path.to_str().unwrap()
+        ));
+        let reversed = geo.reverse_side(&[]).resolve_all();
+        let expanded = geo.expand_includes(&mut read_fragment).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            reversed,
+            Ok(expanded.resolve_all().unwrap().mirror_horizontal())
+        );
+    }
+
+    #[test]
+    fn test_resolve_shared_subtrees() {
+        // Each level splits between two copies of the level below, which
+        // spelled out would be 2^64 solids
+        let mut ufg = Rc::new(UnresolvedFlagGeometry::Solid(Color::Red));
+        for _ in 0..64 {
+            let pivot = Weight::Percent(50.0);
+            ufg = Rc::new(UnresolvedFlagGeometry::Horizontal(ufg.clone(), ufg, pivot));
+        }
+
+        let mut geo = Rc::new(ufg.resolve(&HashMap::new()).unwrap());
+        for _ in 0..64 {
+            geo = match &*geo {
+                FlagGeometry::Horizontal(car, cdr, _) => {
+                    assert!(Rc::ptr_eq(car, cdr));
+                    car.clone()
+                }
+                other => panic!("expected a split, found {:?}", other),
+            };
+        }
+        assert_eq!(*geo, FlagGeometry::Solid(Color::Red));
+    }
+
+    #[test]
+    fn test_resolve_with_registry() {
+        let ufg = |fdl: &str| {
+            SExpr::parse(&mut fdl.chars().peekable())
+                .unwrap()
+                .to_flag_geometry()
+                .unwrap()
+        };
+        let mut registry = HashMap::new();
+        registry.insert("cross".to_string(), Rc::new(ufg("(v 40 (s r) (s w))")));
+        registry.insert("field".to_string(), Rc::new(ufg("(s b)")));
+
+        let geo = ufg("(h 50 (r cross) (t field (s y)))");
+        assert_eq!(
+            geo.resolve_all(),
+            Err(FlagError::UnresolvedReference("cross".to_string()))
+        );
+        assert_eq!(
+            geo.resolve_with_registry(&registry),
+            Ok(flag("(h 50 (v 40 (s r) (s w)) (s y))"))
+        );
+
+        // Tags defined in the flag shadow those in the registry
+        let geo = ufg("(h 50 (r field) (t field (s y)))");
+        assert_eq!(
+            geo.resolve_with_registry(&registry),
+            Ok(flag("(h 50 (s y) (s y))"))
+        );
+    }
+
+    #[test]
+    fn test_render_tag() {
+        let fdl = "(h 33 (t stripe (v 50 (s r) (s g))) (h 50 (s w) (r stripe)))";
+        let ufg = SExpr::parse(&mut fdl.chars().peekable())
+            .unwrap()
+            .to_flag_geometry()
+            .unwrap();
+        assert_eq!(ufg.resolve_tagged("stripe"), Ok(flag("(v 50 (s r) (s g))")));
+        assert_eq!(
+            ufg.resolve_tagged("cross"),
+            Err(FlagError::UnresolvedReference("cross".to_string()))
+        );
+
+        let path = std::env::temp_dir().join("flagrant-test-render-tag.png");
+        let path = path.to_str().unwrap();
+        let args = ["render", "--tag", "stripe", fdl, path]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(run(&args), 0);
+        let rendered = image::open(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        let expected = render(&flag("(v 50 (s r) (s g))"), 400, 300);
+        {
+            use image::GenericImageView;
+            assert_eq!(rendered.dimensions(), (400, 300));
+            for (x, y) in [(0, 0), (399, 149), (0, 150), (399, 299)] {
+                let Rgba([r, g, b, _]) = rendered.get_pixel(x, y);
+                assert_eq!(Rgb([r, g, b]), expected[(x, y)]);
+            }
+        }
+
+        let args = ["render", "--tag", "cross", fdl, path]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(run(&args), EXIT_INVALID_FLAG);
+    }
+
+    #[test]
+    fn test_render_flipped() {
+        let path = std::env::temp_dir().join("flagrant-test-render-flipped.png");
+        let path = path.to_str().unwrap();
+        let corners = |switches: &[&str]| {
+            let mut args = vec!["render".to_string()];
+            args.extend(switches.iter().map(|s| s.to_string()));
+            args.extend(vec![
+                "(h 50 (t canton (v 50 (h 50 (s r) (s y)) (s b))) (s w))".to_string(),
+                path.to_string(),
+            ]);
+            assert_eq!(run(&args), 0);
+
+            use image::GenericImageView;
+            let img = image::open(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            let color = |x, y| {
+                let Rgba([r, g, b, _]) = img.get_pixel(x, y);
+                Rgb([r, g, b])
+            };
+            (color(0, 0), color(399, 0), color(0, 299))
+        };
+
+        let (red, white, blue) = (
+            Color::Red.to_rgb(),
+            Color::White.to_rgb(),
+            Color::Blue.to_rgb(),
+        );
+        assert_eq!(corners(&[]), (red, white, blue));
+        assert_eq!(corners(&["--flip-h"]), (white, red, white));
+        assert_eq!(corners(&["--flip-v"]), (blue, white, red));
+        assert_eq!(corners(&["--flip-h", "--flip-v"]), (white, blue, white));
+
+        // The reverse side is only mirrored left to right, and tags can be
+        // kept the right way round
+        assert_eq!(corners(&["--reverse"]), (white, red, white));
+        assert_eq!(
+            corners(&["--reverse", "--keep", "canton"]),
+            (white, Color::Yellow.to_rgb(), white)
+        );
+        assert_eq!(
+            run(&[
+                "render".to_string(),
+                "--reverse".to_string(),
+                "--keep".to_string()
+            ]),
+            EXIT_USAGE
+        );
+    }
+
+    #[test]
+    fn test_contrast_ratio() {
+        assert!((Color::Black.contrast_ratio(&Color::White) - 21.0).abs() < 0.01);
+        assert!((Color::White.contrast_ratio(&Color::Black) - 21.0).abs() < 0.01);
+        assert_eq!(Color::Red.contrast_ratio(&Color::Red), 1.0);
+        assert!((Color::Blue.contrast_ratio(&Color::White) - 8.59).abs() < 0.01);
+        assert!(Color::Yellow.contrast_ratio(&Color::White) < 1.1);
+    }
+
+    #[test]
+    fn test_emblem() {
+        // A red square with a transparent border
+        let emblem = RgbaImage::from_fn(4, 4, |x, y| {
+            if (1..3).contains(&x) && (1..3).contains(&y) {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        let path = std::env::temp_dir().join("flagrant-test-emblem.png");
+        let path = path.to_str().unwrap();
+        let mut png = Vec::new();
+        image::png::PngEncoder::new(&mut png)
+            .encode(emblem.as_raw(), 4, 4, image::ColorType::Rgba8)
+            .unwrap();
+        std::fs::write(path, png).unwrap();
+
+        let geo = flag(&format!("(emblem \"{}\" 50 (s w))", path));
+        let shadowed = flag(&format!("(emblem \"{}\" 50 (s w) 2 -1 s)", path));
+        std::fs::remove_file(path).unwrap();
+
+        // The shadow falls to the right of and above the red square
+        let img = render(&shadowed, 40, 20);
+        assert_eq!(img[(20, 10)], Color::Red.to_rgb());
+        assert_eq!(img[(20, 7)], Color::Black.to_rgb());
+        assert_eq!(img[(24, 11)], Color::Black.to_rgb());
+        assert_eq!(img[(25, 11)], Color::White.to_rgb());
+        assert_eq!(img[(19, 7)], Color::White.to_rgb());
+        assert_eq!(img[(24, 12)], Color::White.to_rgb());
+
+        let img = render(&geo, 40, 20);
+        assert_eq!(img[(20, 10)], Color::Red.to_rgb());
+        assert_eq!(img[(15, 10)], Color::White.to_rgb());
+        assert_eq!(img[(20, 6)], Color::White.to_rgb());
+        assert_eq!(img[(0, 0)], Color::White.to_rgb());
+
+        let fdl = "(emblem \"/nonexistent/emblem.png\" 50 (s w))";
+        let ufg = SExpr::parse(&mut fdl.chars().peekable())
+            .unwrap()
+            .to_flag_geometry()
+            .unwrap();
+        assert_eq!(
+            ufg.resolve_all(),
+            Err(FlagError::EmblemNotFound(
+                "/nonexistent/emblem.png".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(
+            flag("(h 33 (s b) (h 50 (s w) (s r)))").colors(),
+            vec![Color::Blue, Color::White, Color::Red]
+        );
+        assert_eq!(
+            flag("(v 25 (s r) (v 50 (s w) (s r)))").colors(),
+            vec![Color::Red, Color::White]
+        );
+        assert_eq!(
+            flag("(disc 50 50 30 r (tilefill h 10 w r))").colors(),
+            vec![Color::White, Color::Red]
+        );
+    }
+
+    #[test]
+    fn test_wave() {
+        let img = render(&flag("(v 50 (s w) (s r))"), 40, 20);
+
+        assert_eq!(wave(&img, 0, 10, 0.0), img);
+
+        let waved = wave(&img, 3, 40, 0.0);
+        assert_eq!(waved.dimensions(), img.dimensions());
+        assert_ne!(waved, img);
+
+        // The crest of the wave is a quarter period in, pushing it down
+        assert_eq!(waved[(0, 10)], Color::Red.to_rgb());
+        assert_eq!(waved[(10, 10)], Color::White.to_rgb());
+        assert_eq!(waved[(10, 12)], Color::White.to_rgb());
+        assert_eq!(waved[(10, 13)], Color::Red.to_rgb());
+        assert_eq!(waved[(10, 0)], Color::Black.to_rgb());
+        assert_eq!(waved[(30, 19)], Color::Black.to_rgb());
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(Color::default(), Color::White);
+        assert_eq!(FlagGeometry::default(), flag("(s w)"));
+    }
+
+    #[test]
+    fn test_dialect() {
+        assert_eq!(flag("(solid r)"), flag("(s r)"));
+        assert_eq!(
+            flag("(horizontal 50 (tag x (solid r)) (vertical 50 (s w) (ref x)))"),
+            flag("(h 50 (t x (s r)) (v 50 (s w) (r x)))")
+        );
+
+        let mut dialect = Dialect::default();
+        dialect.add_alias("fill", "solid");
+        dialect.add_alias("stripes", "tilefill");
+        dialect.add_color("brand", Color::Rgb([1, 2, 3]));
+        assert_eq!(
+            parse_flag_in("(h 50 (fill brand) (stripes v 50 r w))", &dialect),
+            Ok(flag("(h 50 (s #010203) (tilefill v 50 r w))"))
+        );
+        assert!(parse_flag("(fill r)").is_err());
+
+        let sexpr = SExpr::parse(&mut "(fill r w)".chars().peekable()).unwrap();
+        assert_eq!(
+            sexpr.to_flag_geometry_in(&dialect).unwrap_err(),
+            FlagError::InvalidExpression("(fill r w)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_draw_layers_clipped() {
+        // Layers as large as the whole area would take tens of gigabytes
+        // here, while a row of them is small
+        let (width, height) = (60_000, 60_000);
+        let geo = flag("(h 50 (s r) (alpha 0.5 (mask (v 50 (s w) (s none)) (s b))))");
+        let mut canvas = RowCanvas {
+            width,
+            height,
+            y: 100,
+            row: vec![0; 1 + 3 * width as usize],
+        };
+        let area = Rect::new(0, 0, width, height);
+        geo.draw_area_clipped(&mut canvas, area, Rect::new(0, 100, width, 1));
+        assert_eq!(canvas.row[1..4], [255, 0, 0]);
+        assert_eq!(canvas.row[3 * 40_000 + 1..3 * 40_000 + 4], [0, 0, 128]);
+
+        // Flipped layers are read from the mirrored row
+        let gradient = FlagGeometry::procedural(|_, y| Rgb([y as u8, 0, 0]));
+        let mut img = RgbImage::new(4, 8);
+        gradient.mirror_vertical().draw_area_clipped(
+            &mut img,
+            Rect::new(0, 0, 4, 8),
+            Rect::new(0, 2, 4, 1),
+        );
+        assert_eq!(img[(0, 2)], Rgb([5, 0, 0]));
+        assert_eq!(img[(0, 3)], Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn test_render_png_streaming() {
+        use image::GenericImageView;
+
+        for fdl in &[
+            "(h 33 (s b) (h 50 (s w) (s r)))",
+            "(rays 8 r y (v 50 (s b) (s none)))",
+            "(disc 50 50 30 r (s w))",
+            "(h 50 (s r) (alpha 0.3 (disc 50 50 40 b (s w))))",
+            "(mask (disc 50 50 30 r (s none)) (tilefill v 10 b y))",
+            "(grid (tilefill h 20 r w) (tilefill v 25 b w) mix)",
+        ] {
+            let geo = flag(fdl);
+            let mut png = Vec::new();
+            render_png_streaming(&geo, 30, 20, &mut png).unwrap();
+
+            let chunks = png_chunks(&png).unwrap();
+            assert_eq!(chunks[0].0, b"IHDR");
+            assert_eq!(chunks.last().unwrap().0, b"IEND");
+            assert_eq!(
+                chunks.iter().filter(|(kind, _)| *kind == b"IDAT").count(),
+                21
+            );
+
+            let streamed = image::load_from_memory(&png).unwrap();
+            let buffered = image::load_from_memory(&render_png(&geo, 30, 20).unwrap()).unwrap();
+            assert_eq!(streamed.dimensions(), (30, 20));
+            for (x, y) in (0..20).flat_map(|y| (0..30).map(move |x| (x, y))) {
+                assert_eq!(
+                    streamed.get_pixel(x, y),
+                    buffered.get_pixel(x, y),
+                    "{}",
+                    fdl
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_operator() {
+        let sexpr = SExpr::parse(&mut "(q 1 (s r))".chars().peekable()).unwrap();
+        assert_eq!(
+            sexpr.to_flag_geometry().unwrap_err(),
+            FlagError::UnknownOperator("q".to_string())
+        );
+
+        let sexpr = SExpr::parse(&mut "(s r w)".chars().peekable()).unwrap();
+        assert_eq!(
+            sexpr.to_flag_geometry().unwrap_err(),
+            FlagError::InvalidExpression("(s r w)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_resolve_shares_tags() {
+        let mut fdl = "(t x (s r))".to_string();
+        for _ in 0..10 {
+            fdl = format!("(h 50 {} (r x))", fdl);
+        }
+
+        let geo = flag(&fdl);
+        let mut references = Vec::new();
+        let mut node = &geo;
+        while let FlagGeometry::Horizontal(car, cdr, _) = node {
+            references.push(cdr.clone());
+            node = car;
+        }
+
+        assert_eq!(references.len(), 10);
+        assert!(references.iter().all(|r| Rc::ptr_eq(r, &references[0])));
+    }
+
+    #[test]
+    fn test_hex_colors() {
+        for hex in &["#ff8000", "ff8000", "0xff8000", "FF8000"] {
+            assert_eq!(hex.parse(), Ok(Color::Rgb([255, 128, 0])), "{}", hex);
+        }
+        assert_eq!("b".parse(), Ok(Color::Blue));
+        for bad in &["#ff80", "ff80000", "0x", "#gg8000", "x0ff8000"] {
+            assert_eq!(bad.parse::<Color>(), Err(()), "{}", bad);
+        }
+
+        let img = render(&flag("(h 50 (s #00ff00) (s 0000ff))"), 2, 1);
+        assert_eq!(img[(0, 0)], Color::Green.to_rgb());
+        assert_eq!(img[(1, 0)], Color::Blue.to_rgb());
+    }
+
+    #[test]
+    fn test_random_flag() {
+        for seed in 0..20 {
+            let a = random_flag(seed, 5);
+            let b = random_flag(seed, 5);
+            assert_eq!(render(&a, 60, 40), render(&b, 60, 40));
+            assert!(a.depth() <= 5);
+        }
+
+        assert_eq!(random_flag(7, 1).depth(), 1);
+    }
+
+    #[test]
+    fn test_approximate_flag() {
+        let gradient = RgbImage::from_fn(100, 10, |x, _| {
+            let t = (x * 255 / 99) as u8;
+            [255 - t, 0, t].into()
+        });
+
+        let geo = approximate_flag(&gradient, 4, Direction::Horizontal);
+        assert_eq!(geo.element_count(), 7);
+
+        let img = render(&geo, 100, 10);
+        let reds = (0..4)
+            .map(|band| img[(band * 25 + 12, 5)].0[0])
+            .collect::<Vec<_>>();
+        assert!(reds.windows(2).all(|w| w[0] > w[1]), "{:?}", reds);
+        assert!(reds[0] > 200 && reds[3] < 55, "{:?}", reds);
+    }
+
+    #[test]
+    fn test_bad_color() {
+        for fdl in &["(s \"# ffffff\")", "(s \"#ff ff00\")", "(s q)"] {
+            let sexpr = SExpr::parse(&mut fdl.chars().peekable()).unwrap();
+            let color = sexpr.list().unwrap()[1].to_string();
+            assert_eq!(
+                sexpr.to_flag_geometry().unwrap_err(),
+                FlagError::BadColor(color)
+            );
+        }
+    }
+
+    #[test]
+    fn test_direction_from_str() {
+        assert_eq!("h".parse(), Ok(Direction::Horizontal));
+        assert_eq!("v".parse(), Ok(Direction::Vertical));
+        assert_eq!("x".parse::<Direction>(), Err(()));
+        assert_eq!("horizontal".parse::<Direction>(), Err(()));
+    }
+
+    #[test]
+    fn test_draw_area_clipped() {
+        let geo = flag("(h 33 (s b) (h 50 (s w) (s r)))");
+        let sentinel = Rgb([1, 2, 3]);
+        let mut img = RgbImage::from_pixel(30, 10, sentinel);
+
+        let full = Rect::new(0, 0, 30, 10);
+        let middle = Rect::new(10, 0, 10, 10);
+        geo.draw_area_clipped(&mut img, full, middle);
+
+        for (x, _, p) in img.enumerate_pixels() {
+            let expected = if (10..20).contains(&x) {
+                Color::White.to_rgb()
+            } else {
+                sentinel
+            };
+            assert_eq!(*p, expected, "column {}", x);
+        }
+    }
+
+    #[test]
+    fn test_render_with_regions() {
+        let geo = flag("(disc 50 50 25 r (h 50 (s w) (s b)))");
+        let (img, regions) = render_with_regions(&geo, 40, 20);
+
+        assert_eq!(regions[0], (Rect::new(0, 0, 20, 20), Color::White));
+        assert_eq!(regions[1], (Rect::new(20, 0, 20, 20), Color::Blue));
+        assert!(regions[2..].iter().all(|(_, color)| *color == Color::Red));
+
+        let (last, color) = regions.last().unwrap();
+        assert_eq!(img[(last.left, last.top)], color.to_rgb());
+    }
+
+    #[test]
+    fn test_render_with_background() {
+        let geo = flag("(disc 50 50 25 r (s none))");
+        let img = render_with_background(&geo, 40, 20, Color::White);
+
+        assert_eq!(img[(20, 10)], Color::Red.to_rgb());
+        for x in 0..40 {
+            assert_eq!(img[(x, 0)], Color::White.to_rgb());
+            assert_eq!(img[(x, 19)], Color::White.to_rgb());
+        }
+        assert_eq!(render(&geo, 40, 20)[(0, 0)], Color::Black.to_rgb());
+    }
+
+    #[test]
+    fn test_geometry_eq() {
+        let tricolor = || {
+            FlagGeometry::Horizontal(
+                Rc::new(FlagGeometry::Solid(Color::Blue)),
+                Rc::new(FlagGeometry::Horizontal(
+                    Rc::new(FlagGeometry::Solid(Color::White)),
+                    Rc::new(FlagGeometry::Solid(Color::Red)),
+                    Weight::Percent(50.0),
+                )),
+                Weight::Percent(33.0),
+            )
+        };
+
+        assert_eq!(tricolor(), tricolor());
+        assert_eq!(flag("(h 33 (s b) (h 50 (s w) (s r)))"), tricolor());
+        assert_ne!(flag("(h 33 (s b) (h 50 (s w) (s g)))"), tricolor());
+    }
+
+    #[test]
+    fn test_mirror_clone() {
+        let original = flag("(h 25 (s r) (v 40 (s w) (s b)))");
+        let copy = original.clone();
+        let mirrored = copy.mirror_horizontal();
+
+        assert_eq!(original, copy);
+        assert_eq!(mirrored, flag("(h 75 (v 40 (s w) (s b)) (s r))"));
+        assert_eq!(mirrored.mirror_horizontal(), original);
+        assert_eq!(
+            original.mirror_vertical(),
+            flag("(h 25 (s r) (v 60 (s b) (s w)))")
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let path = std::env::temp_dir().join("flagrant-test-include.fdl");
+        std::fs::write(&path, "(v 50 (s r) (s w))").unwrap();
+
+        let fdl = format!(
+            "(h 50 (include {0:?}) (include {0:?}))",
+            path.to_str().unwrap()
+        );
+        let geo = flag(&fdl);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(geo, flag("(h 50 (v 50 (s r) (s w)) (v 50 (s r) (s w)))"));
+
+        let mut fragments = |path: &str| match path {
+            "a" => Some("(h 50 (s r) (include b))".to_string()),
+            "b" => Some("(v 50 (s w) (include a))".to_string()),
+            _ => None,
+        };
+        let parse = |fdl: &str| {
+            SExpr::parse(&mut fdl.chars().peekable())
+                .unwrap()
+                .to_flag_geometry()
+                .unwrap()
+        };
+        assert_eq!(
+            parse("(include a)").expand_includes(&mut fragments),
+            Err(FlagError::IncludeCycle("a".to_string()))
+        );
+        assert_eq!(
+            parse("(include c)").expand_includes(&mut fragments),
+            Err(FlagError::IncludeNotFound("c".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_render_cost() {
+        assert_eq!(render_cost(&flag("(s r)"), 400, 300), 120000);
+        assert_eq!(render_cost(&flag("(h 50 (s r) (s w))"), 400, 300), 120000);
+        assert!(render_cost(&flag("(disc 50 50 30 r (s w))"), 400, 300) > 120000);
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_render_data_uri() {
+        use image::GenericImageView;
+
+        let uri = render_data_uri(&flag("(h 50 (s r) (s w))"), 40, 30).unwrap();
+        let encoded = uri.strip_prefix("data:image/png;base64,").unwrap();
+
+        let mut bits = 0u32;
+        let mut count = 0;
+        let mut png = Vec::new();
+        for c in encoded.bytes().take_while(|c| *c != b'=') {
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                _ => 63,
+            };
+            bits = bits << 6 | value as u32;
+            count += 6;
+            if count >= 8 {
+                count -= 8;
+                png.push((bits >> count) as u8);
+            }
+        }
+
+        assert_eq!(
+            png,
+            render_png(&flag("(h 50 (s r) (s w))"), 40, 30).unwrap()
+        );
+        assert_eq!(
+            image::load_from_memory(&png).unwrap().dimensions(),
+            (40, 30)
+        );
+    }
+
+    #[test]
+    fn test_known_colors() {
+        let colors = known_colors();
+        for (code, rgb) in &[
+            ("b", [0, 0, 255]),
+            ("g", [0, 255, 0]),
+            ("r", [255, 0, 0]),
+            ("w", [255, 255, 255]),
+            ("y", [255, 255, 0]),
+            ("s", [0, 0, 0]),
+        ] {
+            assert!(
+                colors.contains(&(*code, Color::Rgb(*rgb).nearest_named())),
+                "missing {}",
+                code
+            );
+        }
+        assert!(colors.contains(&("none", Color::None)));
+
+        for (code, color) in colors {
+            assert_eq!(code.parse(), Ok(color), "{}", code);
+            assert_eq!(color.to_fdl().as_deref(), Some(code));
+        }
+    }
+
+    #[test]
+    fn test_rgba_blend() {
+        let mut img = RgbaImage::new(2, 1);
+        img.rectangle(0, 0, 2, 1, &Color::Red);
+        img.rectangle(1, 0, 1, 1, &Color::Rgba([255, 255, 255, 128]));
+
+        assert_eq!(img[(0, 0)], [255, 0, 0, 255].into());
+        assert_eq!(img[(1, 0)], [255, 128, 128, 255].into());
+    }
+
+    #[test]
+    fn test_render_rgba() {
+        let geo = flag("(h 50 (s r) (alpha 0.5 (s w)))");
+        let img = render_rgba(&geo, 2, 1, AlphaMode::Straight);
+        assert_eq!(img[(0, 0)], Rgba([255, 0, 0, 255]));
+        assert_eq!(img[(1, 0)], Rgba([255, 255, 255, 128]));
+
+        let img = render_rgba(&geo, 2, 1, AlphaMode::Premultiplied);
+        assert_eq!(img[(0, 0)], Rgba([255, 0, 0, 255]));
+        assert_eq!(img[(1, 0)], Rgba([128, 128, 128, 128]));
+
+        // Translucent colors over one another
+        let geo = flag("(alpha 0.5 (h 50 (s r) (s none)))");
+        let img = render_rgba(&geo, 2, 1, AlphaMode::Straight);
+        assert_eq!(img[(1, 0)], Rgba([0, 0, 0, 0]));
+        let mut img = RgbaImage::new(1, 1);
+        img.rectangle(0, 0, 1, 1, &Color::Rgba([255, 0, 0, 128]));
+        img.rectangle(0, 0, 1, 1, &Color::Rgba([0, 0, 255, 128]));
+        assert_eq!(img[(0, 0)], Rgba([85, 0, 170, 192]));
+    }
+
+    #[test]
+    fn test_ppm_canvas() {
+        let flag = FlagGeometry::Horizontal(
+            Rc::new(FlagGeometry::Solid(Color::Red)),
+            Rc::new(FlagGeometry::Solid(Color::Blue)),
+            Weight::Percent(50.0),
+        );
+
+        let mut canvas = PpmCanvas::new(2, 1);
+        flag.draw(&mut canvas);
+
+        let mut out = Vec::new();
+        canvas.write(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "P3\n2 1\n255\n255 0 0 0 0 255\n"
+        );
+
+        // Translucent colors blend like on any other raster canvas, and fully
+        // transparent ones leave the pixel alone
+        let faded = self::flag("(h 50 (alpha 0.5 (s w)) (s none))");
+        faded.draw(&mut canvas);
+        canvas.rectangle(1, 0, 1, 1, &Color::Rgba([255, 255, 255, 0]));
+        let mut img = RgbImage::new(2, 1);
+        flag.draw(&mut img);
+        faded.draw(&mut img);
+        assert_eq!(canvas.pixels, img.pixels().copied().collect::<Vec<_>>());
+        assert_eq!(canvas.pixels[1], Color::Blue.to_rgb());
+        assert_ne!(canvas.pixels[0], Color::White.to_rgb());
     }
 }